@@ -0,0 +1,79 @@
+use crate::object::{write_name, ObjectError, ObjectFile, Reader};
+
+pub const MAGIC: &[u8; 4] = b"SARC";
+const VERSION: u8 = 1;
+
+/// A static library: a list of named objects that the linker only pulls
+/// in when one of their global symbols is needed.
+#[derive(Debug, Clone, Default)]
+pub struct Archive {
+    pub members: Vec<(String, ObjectFile)>,
+}
+
+impl Archive {
+    pub fn is_archive(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+
+    /// Add a member, replacing any existing member with the same name
+    pub fn insert(&mut self, name: String, object: ObjectFile) {
+        match self.members.iter_mut().find(|(member, _)| *member == name) {
+            Some(member) => member.1 = object,
+            None => self.members.push((name, object)),
+        }
+    }
+
+    /// Index of the first member defining a global symbol
+    pub fn find_definition(&self, symbol: &str) -> Option<usize> {
+        self.members.iter().position(|(_, object)| {
+            object.defined_globals().any(|sym| sym.name == symbol)
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::<u8>::new();
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+
+        out.extend_from_slice(&(self.members.len() as u32).to_be_bytes());
+        for (name, object) in &self.members {
+            let bytes = object.to_bytes();
+
+            write_name(&mut out, name);
+            out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+            out.extend_from_slice(&bytes);
+        }
+
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Archive, ObjectError> {
+        let mut reader = Reader { bytes, pos: 0 };
+
+        if reader.take(4)? != MAGIC {
+            return Err(ObjectError("bad archive magic".into()));
+        }
+
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(ObjectError(format!("unsupported archive version {}", version)));
+        }
+
+        let mut archive = Archive::default();
+
+        for _ in 0..reader.u32()? {
+            let name = reader.name()?;
+            let len = reader.u32()? as usize;
+            let object = ObjectFile::from_bytes(reader.take(len)?)
+                .map_err(|e| ObjectError(format!("member '{}': {}", name, e.0)))?;
+
+            archive.members.push((name, object));
+        }
+
+        if reader.pos != bytes.len() {
+            return Err(ObjectError("trailing bytes".into()));
+        }
+
+        Ok(archive)
+    }
+}
@@ -0,0 +1,275 @@
+use std::error::Error;
+
+use crate::assembler::assemble;
+use crate::devices::{board_devices, SevenSegment, BUS_BASE};
+use crate::isa;
+use crate::layout::MemoryLayout;
+use crate::linker::{link, Image};
+use crate::parser::{parse_u32, Segment};
+use crate::simulator::{Machine, StepObserver, StopReason, DEFAULT_MEMORY_SIZE};
+use crate::timing::{TimingConfig, TimingModel};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expectation {
+    Reg(usize, u32),
+    // A data address or label, and the word stored there
+    Mem(String, u32),
+    // Every value shown on the 7-segment display, in order
+    Seg7(Vec<u32>),
+    MaxCycles(u64),
+}
+
+/// The directives in an assembly test, written as comments so the file
+/// still assembles normally:
+///
+/// ```text
+/// # init r15 = 0x100       set a register before running
+/// # expect r1 = 7          final register value
+/// # expect mem[0xfc] = 7   final data word at a data address or label
+/// # expect seg7 = 1, 2     values shown on the 7-segment display
+/// # expect cycles <= 20    upper bound from the default timing model
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TestCase {
+    pub inits: Vec<(usize, u32)>,
+    // Source line of each expectation
+    pub expectations: Vec<(u32, Expectation)>,
+}
+
+fn parse_value(value: &str) -> Result<u32, String> {
+    let value = value.trim();
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, value),
+    };
+
+    parse_u32(digits).map(|v| if negative { v.wrapping_neg() } else { v })
+        .ok_or_else(|| format!("invalid value '{}'", value))
+}
+
+fn parse_reg(reg: &str) -> Result<usize, String> {
+    reg.trim().strip_prefix('r')
+        .and_then(|n| n.parse::<usize>().ok())
+        .filter(|n| *n < 16)
+        .ok_or_else(|| format!("invalid register '{}'", reg.trim()))
+}
+
+fn parse_expectation(text: &str) -> Result<Expectation, String> {
+    if let Some(bound) = text.strip_prefix("cycles") {
+        let bound = bound.trim_start().strip_prefix("<=")
+            .ok_or("expected 'cycles <= <n>'")?;
+
+        return Ok(Expectation::MaxCycles(parse_value(bound)? as u64));
+    }
+
+    let (target, value) = text.split_once('=')
+        .ok_or_else(|| format!("expected '<target> = <value>', got '{}'", text))?;
+    let target = target.trim();
+
+    if target == "seg7" {
+        let values = value.split(',').map(parse_value).collect::<Result<Vec<u32>, String>>()?;
+        return Ok(Expectation::Seg7(values));
+    }
+
+    if let Some(loc) = target.strip_prefix("mem[").and_then(|loc| loc.strip_suffix(']')) {
+        return Ok(Expectation::Mem(loc.trim().into(), parse_value(value)?));
+    }
+
+    Ok(Expectation::Reg(parse_reg(target)?, parse_value(value)?))
+}
+
+pub fn parse_test_case(source: &str) -> Result<TestCase, Box<dyn Error>> {
+    let mut case = TestCase::default();
+
+    for (i, line) in source.lines().enumerate() {
+        let line_no = i as u32 + 1;
+        let Some((_, comment)) = line.split_once('#') else {
+            continue;
+        };
+
+        // Every directive has an '=', so ordinary comments such as
+        // "# init the stack" are left alone
+        let comment = comment.trim();
+        if !comment.contains('=') {
+            continue;
+        }
+
+        if let Some(text) = comment.strip_prefix("expect ") {
+            let expectation = parse_expectation(text.trim())
+                .map_err(|e| format!("line {}: {}", line_no, e))?;
+            case.expectations.push((line_no, expectation));
+        } else if let Some(text) = comment.strip_prefix("init ") {
+            let (reg, value) = text.split_once('=').unwrap();
+            let init = parse_reg(reg).and_then(|reg| Ok((reg, parse_value(value)?)))
+                .map_err(|e| format!("line {}: {}", line_no, e))?;
+            case.inits.push(init);
+        }
+    }
+
+    Ok(case)
+}
+
+/// Records the values the 7-segment display shows after each write to it
+#[derive(Default)]
+struct Seg7Log {
+    values: Vec<u32>,
+}
+
+impl StepObserver for Seg7Log {
+    fn on_step(&mut self, machine: &Machine) -> Result<(), Box<dyn Error>> {
+        if let Some(access) = machine.last.mem_access {
+            // A byte store changes part of the value
+            if access.write && (BUS_BASE..BUS_BASE + 4).contains(&access.addr) {
+                let value = machine.load_word(BUS_BASE)?;
+                if self.values.last() != Some(&value) {
+                    self.values.push(value);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The byte a data address, as the program sees it, or a label refers to
+fn mem_address(image: &Image, loc: &str) -> Result<u32, String> {
+    if let Ok(addr) = parse_value(loc) {
+        return Ok(isa::current().addressing.data_byte(addr));
+    }
+
+    match image.label(loc) {
+        Some((Segment::Data, addr)) => Ok(addr),
+        Some(_) => Err(format!("'{}' is not in .data", loc)),
+        None => Err(format!("no label named '{}'", loc)),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TestResult {
+    pub steps: u64,
+    pub cycles: u64,
+    // One message per expectation that didn't hold
+    pub failures: Vec<String>,
+}
+
+/// Assemble and run one test. Errors are for tests that couldn't be run at
+/// all, e.g. because the source doesn't assemble or the machine faults.
+pub fn run_test(source: &str, max_steps: u64) -> Result<TestResult, Box<dyn Error>> {
+    let case = parse_test_case(source)?;
+    let image = link(&[assemble(source)?], &MemoryLayout::default())?;
+
+    let mut machine = Machine::new(&image, DEFAULT_MEMORY_SIZE)?;
+    machine.devices = board_devices();
+    machine.devices[0].device = Box::new(SevenSegment::quiet());
+
+    for (reg, value) in &case.inits {
+        machine.regs[*reg] = *value;
+    }
+
+    let mut seg7 = Seg7Log::default();
+    let mut timing = TimingModel::new(TimingConfig::default());
+    let reason = machine.run(max_steps, &mut [&mut seg7, &mut timing])?;
+
+    let mut result = TestResult { steps: machine.steps, cycles: timing.cycles(), failures: Vec::new() };
+
+    if reason == StopReason::StepLimit {
+        result.failures.push(format!("did not halt within {} steps", max_steps));
+    }
+
+    for (line, expectation) in &case.expectations {
+        let failure = match expectation {
+            Expectation::Reg(reg, expected) => {
+                let value = machine.regs[*reg];
+                (value != *expected).then(|| format!("r{} = {:#010x}, expected {:#010x}", reg, value, expected))
+            },
+            Expectation::Mem(loc, expected) => {
+                let value = mem_address(&image, loc).map_err(|e| format!("line {}: {}", line, e))
+                    .and_then(|addr| machine.load_data(addr).map_err(|e| e.to_string()))?;
+                (value != *expected).then(|| format!("mem[{}] = {:#010x}, expected {:#010x}", loc, value, expected))
+            },
+            Expectation::Seg7(expected) => {
+                let format = |values: &[u32]| values.iter()
+                    .map(|v| format!("{:#x}", v))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                (seg7.values != *expected).then(|| format!("seg7 showed [{}], expected [{}]", format(&seg7.values), format(expected)))
+            },
+            Expectation::MaxCycles(max) =>
+                (result.cycles > *max).then(|| format!("took {} cycles, expected at most {}", result.cycles, max)),
+        };
+
+        if let Some(failure) = failure {
+            result.failures.push(format!("line {}: {}", line, failure));
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asmtest::{parse_test_case, run_test, Expectation};
+
+    #[test]
+    fn test_expectations() {
+        let source = "
+            # init r15 = 0x100
+            li  r1, 1
+            li  r2, 16
+            shl r3, r1, r2
+            li  r4, 5
+            sw  r4, r3, 0      # 7-seg
+            li  r4, 0x7
+            sw  r4, r3, 0
+            sw  r4, r0, 1
+            # expect r4 = 7
+            # expect mem[4] = 7
+            # expect seg7 = 5, 7
+            # expect r15 = 256
+            # expect cycles <= 30
+        ";
+
+        let case = parse_test_case(source).unwrap();
+        assert_eq!(case.inits, vec![(15, 0x100)]);
+        assert_eq!(case.expectations[0], (11, Expectation::Reg(4, 7)));
+
+        let result = run_test(source, 1000).unwrap();
+        assert_eq!(result.failures, Vec::<String>::new());
+
+        let result = run_test(&source.replace("r4 = 7", "r4 = -1"), 1000).unwrap();
+        assert_eq!(result.failures, vec!["line 11: r4 = 0x00000007, expected 0xffffffff".to_string()]);
+    }
+
+    #[test]
+    fn test_seg7_byte_stores() {
+        let source = "
+            li  r1, 1
+            li  r2, 16
+            shl r3, r1, r2
+            li  r4, 0x12
+            sb  r4, r3, 2
+            li  r4, 0x34
+            sb  r4, r3, 3
+            # expect seg7 = 0x1200, 0x1234
+        ";
+
+        let result = run_test(source, 1000).unwrap();
+        assert_eq!(result.failures, Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_plain_comments() {
+        let source = "
+            # init the stack
+            li r15, 0x100
+            # expect the loop to finish
+            # expect r15 = 0x100
+        ";
+
+        let case = parse_test_case(source).unwrap();
+        assert!(case.inits.is_empty());
+        assert_eq!(case.expectations, vec![(5, Expectation::Reg(15, 0x100))]);
+
+        assert!(parse_test_case("# expect r1 = x").is_err());
+    }
+}
@@ -0,0 +1,325 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+
+use crate::bytecode::encode_instruction;
+use crate::instruction::{make_insns, name_to_op, Mnemonic};
+use crate::isa::{self, Operation};
+use crate::object::{ObjectFile, RelocKind, Relocation, Symbol};
+use crate::parser::{self, parse_operand, AsmObject, Operand, Segment};
+
+enum TextItem {
+    Instruction(Mnemonic, Vec<Operand>),
+    Word(Vec<Operand>),
+}
+
+impl TextItem {
+    fn len(&self) -> usize {
+        match self {
+            TextItem::Instruction(op, _) => op.length() as usize,
+            TextItem::Word(values) => values.len(),
+        }
+    }
+}
+
+fn reloc_kind(op: &Mnemonic) -> Option<RelocKind> {
+    match op.label_def()?.operation {
+        Operation::Jump => Some(RelocKind::Jump),
+        Operation::Li => Some(RelocKind::Imm8),
+        Operation::Branch(_) => Some(RelocKind::Branch),
+        _ => None
+    }
+}
+
+/// Assemble source into a relocatable object.
+///
+/// Branches to labels in the same `.text` are resolved here, everything
+/// else that refers to a label becomes a relocation for the linker.
+///
+/// Labels are recorded in instructions for .text and bytes for .data, the
+/// addresses programs see come from `isa::Addressing`. So does the operand
+/// of `.org`, which moves forward to that address from the start of the
+/// section in this object.
+pub fn assemble(input: &str) -> Result<ObjectFile, Box<dyn Error>> {
+    let objects = parser::parse_asm(input)?;
+    let addressing = isa::current().addressing;
+    let data_word = addressing.data_word_bytes;
+
+    let mut constants = HashMap::<String, String>::new();
+
+    // Do two passes incase a constant relies on another constant
+    for _i in 1..2 {
+        for (_, obj) in &objects {
+            if let AsmObject::Constant(key, value) = obj {
+                let mut value = value;
+                while constants.contains_key(value) {
+                    value = constants.get(value).unwrap();
+                }
+
+                constants.insert(key.into(), value.into());
+            }
+        }
+    }
+
+    let substitute = |operands: &[Operand]| -> Vec<Operand> {
+        operands.iter().map(|op| {
+            if let Operand::Name(name) = op {
+                if let Some(value) = constants.get(name) {
+                    return parse_operand(value).unwrap().1;
+                }
+            }
+
+            op.to_owned()
+        }).collect()
+    };
+
+    let mut labels = HashMap::<String, (Segment, u32)>::new();
+    let mut globals = Vec::<String>::new();
+    let mut externs = HashSet::<String>::new();
+    let mut text = Vec::<(u32, TextItem)>::new();
+    let mut data = Vec::<(u32, Vec<Operand>)>::new();
+    let mut segment = Segment::Text;
+    let mut pc = 0;
+    let mut data_pc = 0;
+    for (line, obj) in objects {
+        match obj {
+            AsmObject::Instruction(name, operands) => {
+                if segment != Segment::Text {
+                    return Err(format!("Instruction '{}' outside of .text", name).into());
+                }
+
+                let op = name_to_op(name.as_str())?;
+                pc += op.length();
+
+                text.push((line, TextItem::Instruction(op, substitute(&operands))));
+            },
+            AsmObject::Label(name) => {
+                let addr = match segment {
+                    Segment::Text => pc,
+                    Segment::Data => data_pc,
+                };
+
+                labels.insert(name, (segment, addr));
+            },
+            AsmObject::Directive(name, operands) => match (name.as_str(), &operands[..]) {
+                ("text", []) => segment = Segment::Text,
+                ("data", []) => segment = Segment::Data,
+                ("global", names) | ("extern", names) => {
+                    for op in names {
+                        let Operand::Name(sym) = op else {
+                            return Err(format!("Expected a symbol name for .{}, got '{}'", name, op).into());
+                        };
+
+                        if name == "global" {
+                            globals.push(sym.clone());
+                        } else {
+                            externs.insert(sym.clone());
+                        }
+                    }
+                },
+                ("word", values) if !values.is_empty() => match segment {
+                    Segment::Text => {
+                        pc += values.len() as u32;
+                        text.push((line, TextItem::Word(substitute(values))));
+                    },
+                    Segment::Data => {
+                        data.push((data_pc, substitute(values)));
+                        data_pc += data_word * values.len() as u32;
+                    }
+                },
+                ("org", [value]) => {
+                    let Operand::Immediate(addr) = substitute(std::slice::from_ref(value))[0] else {
+                        return Err(format!("Expected an address for .org, got '{}'", value).into());
+                    };
+
+                    match segment {
+                        Segment::Text => {
+                            let target = addressing.text_index(addr as u32)
+                                .ok_or_else(|| format!(".org {:#x} is not the address of an instruction", addr))?;
+                            if target < pc {
+                                return Err(format!(".org {:#x} is before the current address", addr).into());
+                            }
+
+                            // Padded with zero words
+                            let padding = vec![Operand::Immediate(0); (target - pc) as usize];
+                            pc = target;
+                            text.push((line, TextItem::Word(padding)));
+                        },
+                        Segment::Data => {
+                            let target = addressing.data_byte(addr as u32);
+                            if target < data_pc {
+                                return Err(format!(".org {:#x} is before the current address", addr).into());
+                            }
+
+                            // No values, but the data is padded up to it
+                            data_pc = target;
+                            data.push((data_pc, Vec::new()));
+                        }
+                    }
+                },
+                _ => return Err(format!("Invalid directive: '.{}'", name).into())
+            },
+            _ =>
+                ()
+        }
+    }
+
+    let mut object = ObjectFile::default();
+
+    for (line, item) in &text {
+        object.lines.extend(std::iter::repeat_n(*line, item.len()));
+
+        let (op, ops) = match item {
+            TextItem::Instruction(op, ops) => (op, ops),
+            TextItem::Word(values) => {
+                for value in values {
+                    let Operand::Immediate(value) = value else {
+                        return Err(format!("Expected an immediate for .word in .text, got '{}'", value).into());
+                    };
+
+                    object.text.push(*value as u16);
+                }
+
+                continue;
+            }
+        };
+
+        // The location counter, in step with the label addresses of the first
+        // pass as both count every word a pseudo-instruction expands to
+        let addr = object.text.len() as u32;
+        let mut operands: Vec<Operand> = Vec::<Operand>::new();
+
+        for operand in ops {
+            let Operand::Name(name) = operand else {
+                // Jumps and branches can only land on the start of an instruction
+                if let (Some(RelocKind::Jump | RelocKind::Branch), Operand::Immediate(value)) = (reloc_kind(op), operand) {
+                    if *value % addressing.text_step() as i32 != 0 {
+                        return Err(format!("{} {} does not go to the start of an instruction", op.name(), value).into());
+                    }
+                }
+
+                operands.push(operand.clone());
+                continue;
+            };
+
+            let local = labels.get(name);
+            if local.is_none() && !externs.contains(name) {
+                return Err(format!("Invalid label: {}", name).into());
+            }
+
+            let Some(kind) = reloc_kind(op) else {
+                return Err(format!("Label '{}' cannot be used as an operand of {}", name, op.name()).into());
+            };
+
+            match local {
+                Some((Segment::Text, label_addr)) if kind == RelocKind::Branch => {
+                    // Signed, from the instruction after this one to the label
+                    let off = (*label_addr as i32 - (addr as i32 + 1)) * addressing.text_step() as i32;
+
+                    let field = op.label_def().and_then(|def| def.field("off")).unwrap();
+                    if !field.holds(off as i64) {
+                        return Err(format!("Branch to '{}' is out of range, {} does not fit in {}-bit offset", name, off, field.width).into());
+                    }
+
+                    operands.push(Operand::Immediate(off));
+                },
+                Some((Segment::Data, _)) if kind == RelocKind::Branch => {
+                    return Err(format!("Cannot branch to data label '{}'", name).into());
+                },
+                _ => {
+                    object.relocations.push(Relocation {
+                        section: Segment::Text,
+                        offset: addr,
+                        kind,
+                        symbol: name.clone(),
+                    });
+
+                    operands.push(Operand::Immediate(0));
+                }
+            }
+        }
+
+        let mut real_insns =
+            make_insns(op, operands.as_slice())?
+            .iter()
+            .map(|insn| encode_instruction(*insn))
+            .collect::<Vec<u16>>();
+
+        object.text.append(&mut real_insns);
+    }
+
+    for (offset, values) in data {
+        object.data.resize(offset as usize, 0);
+
+        for (i, value) in values.iter().enumerate() {
+            let offset = offset + data_word * i as u32;
+
+            let word = match value {
+                Operand::Immediate(value) => *value as u32,
+                Operand::Name(name) if labels.contains_key(name) || externs.contains(name) => {
+                    object.relocations.push(Relocation {
+                        section: Segment::Data,
+                        offset,
+                        kind: RelocKind::Word32,
+                        symbol: name.clone(),
+                    });
+
+                    0
+                },
+                Operand::Name(name) => return Err(format!("Invalid label: {}", name).into()),
+                Operand::Register(_) => return Err(format!("Expected a value for .word, got 'r{}'", value).into()),
+            };
+
+            object.data.extend_from_slice(&word.to_be_bytes()[(4 - data_word) as usize..]);
+        }
+    }
+
+    for name in &globals {
+        if !labels.contains_key(name) {
+            return Err(format!("Global symbol '{}' is never defined", name).into());
+        }
+    }
+
+    for (name, (section, value)) in labels {
+        let global = globals.contains(&name);
+        object.symbols.push(Symbol { name, section: Some(section), value, global });
+    }
+
+    for name in externs {
+        if object.symbol(&name).is_none() {
+            object.symbols.push(Symbol { name, section: None, value: 0, global: true });
+        }
+    }
+
+    // Keep the symbol table stable between runs
+    object.symbols.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(object)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble;
+    use crate::bytecode::{Condition, Instruction};
+    use crate::isa;
+
+    // Data words and padding in .text move the label and the branch alike
+    #[test]
+    fn test_branch_past_words_and_org() {
+        let source = "
+        start:
+            beq end
+            .word 1, 2
+            push r1
+            .org 10
+        end:
+            bne start
+        ";
+        let object = assemble(source).unwrap();
+        assert_eq!(object.symbol("end").unwrap().value, 10);
+
+        let decode = |idx: usize| isa::current().decode(object.text[idx]).unwrap();
+        assert_eq!(decode(0), Instruction::Branch(Condition::Equal, 9));
+        // -11 in the 9-bit offset field
+        assert_eq!(decode(10), Instruction::Branch(Condition::NotEqual, 0x1f5));
+    }
+}
@@ -1,5 +1,7 @@
 use std::error;
 
+use crate::isa;
+
 #[derive(Debug, Clone)]
 pub struct InvalidInstruction(pub String);
 
@@ -11,154 +13,156 @@ impl std::fmt::Display for InvalidInstruction {
 
 impl error::Error for InvalidInstruction{}
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Condition {
-    Equal = 0b00,
-    NotEqual = 0b01,
-    LessThan = 0b10,
-    GreaterThanEqual = 0b11
+    Equal,
+    NotEqual,
+    LessThan,
+    GreaterThanEqual
 }
 
-#[derive(Debug, Clone, Copy)]
+/// The operations the machine implements. Their encodings, mnemonics and
+/// operands are in the table in `isa`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Opcode {
-    Add = 0b00000,
-    Sub = 0b00001,
-    Or  = 0b00010,
-    And = 0b00011,
-    Xor = 0b00100,
-    Not = 0b00101,
-    Shl = 0b00110,
-    Shr = 0b00111,
-    Lw = 0b10000,
-    Sw = 0b11000,
-    Branch = 0b11100,
-    Jump = 0b11101,
-    JumpReg = 0b11111,
-    Li = 0b10100
-    //Cmp = 0b01001,
-}
-
-fn opcode_to_enum(opcode: u16) -> Result<Opcode, InvalidInstruction> {
-    match opcode {
-        0b00000 => Ok(Opcode::Add),
-        0b00001 => Ok(Opcode::Sub),
-        0b00010 => Ok(Opcode::Or),
-        0b00011 => Ok(Opcode::And),
-        0b00100 => Ok(Opcode::Xor),
-        0b00101 => Ok(Opcode::Not),
-        0b00110 => Ok(Opcode::Shl),
-        0b00111 => Ok(Opcode::Shr),
-        0b10000 => Ok(Opcode::Lw),
-        0b11000 => Ok(Opcode::Sw),
-        0b11100 => Ok(Opcode::Branch),
-        0b11101 => Ok(Opcode::Jump),
-        0b11111 => Ok(Opcode::JumpReg),
-        0b10100 => Ok(Opcode::Li),
-        _ => Err(InvalidInstruction(format!("Unknown opcode: {:#06x}", opcode)))
-    }
-}
-
-impl std::fmt::Display for Opcode {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let name = match self {
-            Opcode::Add => "add",
-            Opcode::Sub => "sub",
-            Opcode::Or => "or",
-            Opcode::And => "and",
-            Opcode::Xor => "xor",
-            Opcode::Not => "not",
-            Opcode::Shl => "shl",
-            Opcode::Shr => "shr",
-            Opcode::Lw => "lw",
-            Opcode::Sw => "sw",
-            Opcode::Branch => "branch",
-            Opcode::Jump => "jmp(i)",
-            Opcode::JumpReg => "jmp(r)",
-            Opcode::Li => "li"
-        };
-        write!(f, "{}", name)
-    }
+    Add,
+    Sub,
+    Or,
+    And,
+    Xor,
+    Not,
+    Shl,
+    Shr,
+    Lw,
+    Sw,
+    // A byte, sign or zero extended by loads
+    Lb,
+    Lbu,
+    Sb,
+    // The extension, see isa::Isa::extended
+    Mul,
+    Cmp,
+    Sar,
+    Rotr,
+    Rotl,
+    Addi,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instruction {
-    Nop,
     Alu(Opcode, u16, u16, u16),
     Mem(Opcode, u16, u16, u16),
+    // rd, imm
+    AluImm(Opcode, u16, u16),
     Branch(Condition, u16),
     Jump(u16),
     JumpReg(u16),
+    // rd, rt: jump to rt, leaving the return address in rd
+    JumpLink(u16, u16),
     Li(u16, u16)
 }
 
-fn encode_alu_instruction(opcode: Opcode, rd: u16, rt: u16, rs: u16) -> u16 {
-    assert!(rs < 8);
+/// Panics if an operand doesn't fit its field, the assembler checks them first
+pub fn encode_instruction(instr: Instruction) -> u16 {
+    isa::current().encode(instr).unwrap()
+}
 
-    ((opcode as u16) << 11) | ((rs as u16) << 8) | ((rt as u16) << 4) | rd as u16
+/// The inverse of `encode_instruction`. Words that no instruction encodes
+/// to, including those with bits set outside any field, are invalid.
+pub fn decode(instr: u16) -> Result<Instruction, InvalidInstruction> {
+    isa::current().decode(instr)
 }
 
-fn encode_mem_instruction(opcode: Opcode, rd: u16, rt: u16, off: u16) -> u16 {
-    ((opcode as u16) << 11) | ((off as u16) << 8) | ((rt as u16) << 4) | rd as u16
+/// Render an instruction word as text, in the syntax the assembler accepts
+pub fn format_instruction(instr: u16) -> Result<String, InvalidInstruction> {
+    isa::current().format(decode(instr)?)
 }
 
-pub fn encode_instruction(instr: Instruction) -> u16 {
-    match instr {
-        Instruction::Nop => 0,
-        Instruction::Alu(opcode, rd, rt, rs) =>
-            encode_alu_instruction(opcode, rd, rt, rs),
-        Instruction::Mem(opcode, rd, rt, off) =>
-            encode_mem_instruction(opcode, rd, rt, off),
-        Instruction::Branch(cond, off) => {
-            assert!(off <= 0b111111111);
-            (Opcode::Branch as u16) | ((cond as u16) << 9) | (off as u16)
-        },
-        Instruction::Jump(off) => {
-            assert!(off <= 0b11111111111);
-            (Opcode::Jump as u16) | off
-        },
-        Instruction::Li(reg, val) => {
-            // ooooo ddd IIIIIIII
-
-            assert!(val <= 0b11111111);
-            ((Opcode::Li as u16) << 11) | (reg << 8) | (val)
-        },
-        Instruction::JumpReg(reg) => {
-            eprintln!("warn: jmp <reg> unimplemented");
-
-            ((Opcode::JumpReg as u16) << 11) | reg
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::{decode, encode_instruction, Instruction};
+    use crate::isa::{self, FieldKind, Isa};
+
+    /// Every instruction the encoder accepts, with each field over its full range
+    fn all_instructions(isa: &Isa) -> Vec<Instruction> {
+        let mut insns = Vec::<Instruction>::new();
+
+        for def in &isa.instructions {
+            let mut values = vec![Vec::<(&str, u16)>::new()];
+
+            for field in &def.fields {
+                if def.fixed.iter().any(|(name, _)| *name == field.name) {
+                    continue;
+                }
+
+                values = values.iter()
+                    .flat_map(|prefix| (0..1 << field.width).map(move |v| {
+                        let mut next = prefix.clone();
+                        next.push((field.name.as_str(), v));
+                        next
+                    }))
+                    .collect();
+            }
+
+            insns.extend(values.iter().map(|values| Instruction::from_fields(def.operation, |name| {
+                values.iter().find(|(field, _)| *field == name).map(|(_, v)| *v)
+            })));
         }
+
+        insns
     }
-}
 
-pub fn disassemble(instr: u16) {
-    let opcode = (instr >> 11) & 0b11111;
-    let rs = (instr >> 8) & 0b111;
-    let rt = (instr >> 4) & 0b1111;
-    let rd = instr & 0b1111;
-    let mem_off = (instr >> 8) & 0b111;
-    let off = instr & 0b111111111;
-    let imm = instr & 0b11111111;
-    let cond = (instr >> 9) & 0b11;
-    let opcode = opcode_to_enum(opcode).unwrap();
-
-    match opcode {
-        Opcode::Add | Opcode::Sub | Opcode::Or | Opcode::And | Opcode::Xor | Opcode::Not | Opcode::Shl | Opcode::Shr => {
-            println!("{} r{}, r{}, r{}", opcode, rd, rt, rs);
-        },
-        Opcode::Lw | Opcode::Sw => {
-            println!("{} r{}, r{}, {}", opcode, rd, rt, mem_off);
-        },
-        Opcode::Branch => {
-            println!("{} {} r{}, {}", cond, opcode, rt, off);
-        },
-        Opcode::Jump => {
-            println!("{} {}", opcode, off);
-        },
-        Opcode::JumpReg => {
-            println!("jmp r{}", rd);
-        },
-        Opcode::Li => {
-            println!("{} r{}, {}", opcode, rs, imm);
+    #[test]
+    fn test_decode_encode_round_trip() {
+        let insns = all_instructions(isa::current());
+        assert_eq!(insns.len(), 8 * 2048 + 5 * 2048 + 4 * 512 + 2048 + 16 + 256 + 2048);
+
+        for insn in insns {
+            assert_eq!(decode(encode_instruction(insn)).unwrap(), insn, "{:?}", insn);
+        }
+    }
+
+    #[test]
+    fn test_encode_decode_round_trip() {
+        // Every valid word decodes to the instruction that encodes back to it
+        let mut valid = 0;
+        for word in 0..=u16::MAX {
+            if let Ok(insn) = decode(word) {
+                assert_eq!(encode_instruction(insn), word, "{:?}", insn);
+                valid += 1;
+            }
+        }
+
+        assert_eq!(valid, all_instructions(isa::current()).len());
+    }
+
+    #[test]
+    fn test_extended_round_trip() {
+        let isa = Isa::extended();
+        let insns = all_instructions(&isa);
+        assert_eq!(insns.len(), all_instructions(&Isa::builtin()).len() + 4 * 2048 + 128 + 2048);
+
+        for insn in &insns {
+            assert_eq!(isa.decode(isa.encode(*insn).unwrap()).unwrap(), *insn, "{:?}", insn);
+        }
+
+        let valid = (0..=u16::MAX).filter(|word| isa.decode(*word).is_ok()).count();
+        assert_eq!(valid, insns.len());
+    }
+
+    #[test]
+    fn test_fields_fit_the_word() {
+        // Fields of an encoding never overlap each other or the opcode
+        let isa = Isa::extended();
+
+        for def in &isa.instructions {
+            let mut used = isa.opcode.mask();
+
+            for field in &def.fields {
+                assert_eq!(used & field.mask(), 0, "{} overlaps in {}", field.name, def.mnemonic);
+                assert!(field.kind == FieldKind::Imm || 1 << field.width <= isa.registers, "{}", def.mnemonic);
+                used |= field.mask();
+            }
         }
     }
 }
@@ -0,0 +1,453 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::bytecode::Instruction;
+use crate::disasm::{disassemble, DisasmItem};
+use crate::isa;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    Fallthrough,
+    Taken,
+    Jump,
+    // jmp rN or jalr, to a return address or label loaded by li
+    Indirect,
+}
+
+impl EdgeKind {
+    fn name(self) -> &'static str {
+        match self {
+            EdgeKind::Fallthrough => "fallthrough",
+            EdgeKind::Taken => "taken",
+            EdgeKind::Jump => "jump",
+            EdgeKind::Indirect => "indirect",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edge {
+    pub to: u32,
+    pub kind: EdgeKind,
+    // Closes a loop, found as a back edge of a depth-first search from the entry
+    pub back: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub start: u32,
+    // One past the last instruction
+    pub end: u32,
+    pub name: String,
+    pub insns: Vec<DisasmItem>,
+    pub succs: Vec<Edge>,
+    pub reachable: bool,
+    pub loop_header: bool,
+}
+
+/// Basic blocks of a program, in address order. The first is the entry.
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+}
+
+fn ends_block(insn: Option<Instruction>) -> bool {
+    matches!(insn, Some(Instruction::Branch(..) | Instruction::Jump(_) | Instruction::JumpReg(_) | Instruction::JumpLink(..)))
+}
+
+/// Split the words loaded at `base` into basic blocks, naming blocks after
+/// `labels` where there is one for their first address.
+///
+/// Indirect jumps are assumed to go to any address in the program that a
+/// `li` loads, if it has a label or follows a jump, and to the return
+/// address of every `jalr`. That's how calls and returns are set up.
+pub fn build(words: &[u16], base: u32, labels: &HashMap<u32, String>) -> Cfg {
+    // No entry block, e.g. a program with only .data
+    if words.is_empty() {
+        return Cfg { blocks: vec![] };
+    }
+
+    let items = disassemble(words, base);
+    let end = base + words.len() as u32;
+    let in_program = |addr: &u32| (base..end).contains(addr);
+
+    let indirect_targets = items.iter()
+        .filter_map(|item| match item.insn {
+            Some(Instruction::Li(_, imm)) => isa::current().addressing.text_index(imm as u32),
+            _ => None,
+        })
+        .filter(|addr| in_program(addr))
+        .filter(|addr| labels.contains_key(addr) || (*addr > base && ends_block(items[(addr - base - 1) as usize].insn)))
+        .chain(items.iter().filter(|item| matches!(item.insn, Some(Instruction::JumpLink(..)))).map(|item| item.addr + 1).filter(in_program))
+        .collect::<BTreeSet<u32>>();
+
+    let mut leaders = BTreeSet::from([base]);
+    leaders.extend(items.iter().filter_map(|item| item.target).filter(in_program));
+    leaders.extend(items.iter().filter(|item| ends_block(item.insn)).map(|item| item.addr + 1).filter(in_program));
+    leaders.extend(indirect_targets.iter().copied());
+    leaders.extend(labels.keys().copied().filter(in_program));
+
+    let starts = leaders.iter().copied().collect::<Vec<u32>>();
+    let mut blocks = Vec::<Block>::new();
+
+    for (i, start) in starts.iter().enumerate() {
+        let block_end = starts.get(i + 1).copied().unwrap_or(end);
+        let insns = items[(start - base) as usize..(block_end - base) as usize].to_vec();
+        let last = insns.last().unwrap();
+
+        let mut succs = Vec::<Edge>::new();
+        let mut edge = |to: u32, kind: EdgeKind| {
+            if in_program(&to) {
+                succs.push(Edge { to, kind, back: false });
+            }
+        };
+
+        match last.insn {
+            Some(Instruction::Branch(..)) => {
+                // No target if it isn't the start of an instruction
+                if let Some(target) = last.target {
+                    edge(target, EdgeKind::Taken);
+                }
+                edge(block_end, EdgeKind::Fallthrough);
+            },
+            Some(Instruction::Jump(_)) => {
+                if let Some(target) = last.target {
+                    edge(target, EdgeKind::Jump);
+                }
+            },
+            Some(Instruction::JumpReg(_) | Instruction::JumpLink(..)) => {
+                for target in &indirect_targets {
+                    edge(*target, EdgeKind::Indirect);
+                }
+            },
+            _ => edge(block_end, EdgeKind::Fallthrough),
+        }
+
+        blocks.push(Block {
+            start: *start,
+            end: block_end,
+            name: labels.get(start).cloned().unwrap_or_else(|| format!("L{:04x}", start)),
+            insns,
+            succs,
+            reachable: false,
+            loop_header: false,
+        });
+    }
+
+    let mut cfg = Cfg { blocks };
+    cfg.find_loops();
+    cfg
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Visit {
+    New,
+    OnStack,
+    Done,
+}
+
+impl Cfg {
+    fn index(&self, addr: u32) -> usize {
+        self.blocks.binary_search_by_key(&addr, |block| block.start).unwrap()
+    }
+
+    /// Mark reachable blocks and back edges with an iterative depth-first search
+    fn find_loops(&mut self) {
+        if self.blocks.is_empty() {
+            return;
+        }
+
+        let mut state = vec![Visit::New; self.blocks.len()];
+        // Block index and the next successor to look at
+        let mut stack = vec![(0, 0)];
+        state[0] = Visit::OnStack;
+
+        while let Some((block, succ)) = stack.pop() {
+            self.blocks[block].reachable = true;
+
+            let Some(edge) = self.blocks[block].succs.get(succ) else {
+                state[block] = Visit::Done;
+                continue;
+            };
+
+            let next = self.index(edge.to);
+            stack.push((block, succ + 1));
+
+            match state[next] {
+                Visit::New => {
+                    state[next] = Visit::OnStack;
+                    stack.push((next, 0));
+                },
+                Visit::OnStack => {
+                    self.blocks[block].succs[succ].back = true;
+                    self.blocks[next].loop_header = true;
+                },
+                Visit::Done => (),
+            }
+        }
+    }
+
+    /// The calls made with a `jalr` to an address that a `li` earlier in the
+    /// same block loads, or with a `jmp` after a `li` that loads the address
+    /// following it, its return address. Functions start at the entry and at
+    /// every address called, and each runs up to the next.
+    pub fn call_graph(&self) -> CallGraph {
+        let mut sites = Vec::<(u32, u32)>::new();
+
+        for block in &self.blocks {
+            // Registers holding an address loaded by li
+            let mut loaded = HashMap::<u16, u32>::new();
+
+            for item in &block.insns {
+                match item.insn {
+                    Some(Instruction::Li(rd, imm)) => match isa::current().addressing.text_index(imm as u32) {
+                        Some(addr) => { loaded.insert(rd, addr); },
+                        None => { loaded.remove(&rd); },
+                    },
+                    Some(Instruction::Alu(_, rd, ..) | Instruction::AluImm(_, rd, _) | Instruction::Mem(_, rd, ..)) => {
+                        loaded.remove(&rd);
+                    },
+                    _ => (),
+                }
+            }
+
+            let last = block.insns.last().unwrap();
+            let callee = match last.insn {
+                Some(Instruction::JumpLink(_, rt)) => loaded.get(&rt).copied(),
+                Some(Instruction::Jump(_)) if loaded.values().any(|addr| *addr == last.addr + 1) => last.target,
+                _ => None,
+            };
+
+            if let Some(callee) = callee.filter(|callee| self.blocks.binary_search_by_key(callee, |block| block.start).is_ok()) {
+                sites.push((last.addr, callee));
+            }
+        }
+
+        let Some(entry) = self.blocks.first() else {
+            return CallGraph { functions: vec![], calls: vec![] };
+        };
+
+        let mut starts = BTreeSet::from([entry.start]);
+        starts.extend(sites.iter().map(|(_, callee)| *callee));
+
+        let calls = sites.iter()
+            .map(|(site, callee)| (*starts.range(..=site).next_back().unwrap(), *callee))
+            .collect::<BTreeSet<(u32, u32)>>();
+
+        CallGraph {
+            functions: starts.iter().map(|start| (*start, self.blocks[self.index(*start)].name.clone())).collect(),
+            calls: calls.into_iter().collect(),
+        }
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph cfg {\n    node [shape=box, fontname=monospace];\n");
+
+        for block in &self.blocks {
+            let mut text = format!("{}:\\l", escape(&block.name));
+            for item in &block.insns {
+                text += &format!("{:04x}  {}\\l", item.addr, escape(&item.text));
+            }
+
+            let mut attrs = format!("label=\"{}\"", text);
+            if !block.reachable {
+                attrs += ", style=dashed, color=gray";
+            } else if block.loop_header {
+                attrs += ", color=blue";
+            }
+
+            out += &format!("    \"{}\" [{}];\n", escape(&block.name), attrs);
+        }
+
+        let names = self.blocks.iter().map(|block| (block.start, &block.name)).collect::<BTreeMap<u32, &String>>();
+        for block in &self.blocks {
+            for edge in &block.succs {
+                let mut attrs = format!("label=\"{}\"", edge.kind.name());
+                if edge.kind == EdgeKind::Indirect {
+                    attrs += ", style=dotted";
+                }
+                if edge.back {
+                    attrs += ", color=blue";
+                }
+
+                out += &format!("    \"{}\" -> \"{}\" [{}];\n", escape(&block.name), escape(names[&edge.to]), attrs);
+            }
+        }
+
+        out + "}\n"
+    }
+
+    pub fn to_json(&self) -> String {
+        let blocks = self.blocks.iter().map(|block| {
+            let insns = block.insns.iter()
+                .map(|item| format!("{{\"addr\": {}, \"word\": {}, \"text\": \"{}\"}}", item.addr, item.word, escape(&item.text)))
+                .collect::<Vec<String>>();
+            let succs = block.succs.iter()
+                .map(|edge| format!("{{\"to\": {}, \"kind\": \"{}\", \"back\": {}}}", edge.to, edge.kind.name(), edge.back))
+                .collect::<Vec<String>>();
+
+            format!(
+                "    {{\"name\": \"{}\", \"start\": {}, \"end\": {}, \"reachable\": {}, \"loop_header\": {},\n     \"insns\": [{}],\n     \"succs\": [{}]}}",
+                escape(&block.name), block.start, block.end, block.reachable, block.loop_header, insns.join(", "), succs.join(", ")
+            )
+        }).collect::<Vec<String>>();
+
+        format!("{{\"blocks\": [\n{}\n]}}\n", blocks.join(",\n"))
+    }
+}
+
+/// Functions of a program and the calls between them, by start address
+#[derive(Debug, Clone)]
+pub struct CallGraph {
+    // Start and name, in address order. The first is the entry.
+    pub functions: Vec<(u32, String)>,
+    // Caller and callee, each called once however many call sites there are
+    pub calls: Vec<(u32, u32)>,
+}
+
+impl CallGraph {
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph calls {\n    node [shape=box, fontname=monospace];\n");
+
+        let names = self.functions.iter().map(|(start, name)| (*start, name)).collect::<BTreeMap<u32, &String>>();
+        for (_, name) in &self.functions {
+            out += &format!("    \"{}\";\n", escape(name));
+        }
+        for (caller, callee) in &self.calls {
+            out += &format!("    \"{}\" -> \"{}\";\n", escape(names[caller]), escape(names[callee]));
+        }
+
+        out + "}\n"
+    }
+
+    pub fn to_json(&self) -> String {
+        let functions = self.functions.iter()
+            .map(|(start, name)| format!("{{\"name\": \"{}\", \"start\": {}}}", escape(name), start))
+            .collect::<Vec<String>>();
+        let calls = self.calls.iter()
+            .map(|(caller, callee)| format!("{{\"caller\": {}, \"callee\": {}}}", caller, callee))
+            .collect::<Vec<String>>();
+
+        format!("{{\"functions\": [{}],\n \"calls\": [{}]}}\n", functions.join(", "), calls.join(", "))
+    }
+}
+
+/// Escape for the inside of a quoted string, in DOT and JSON alike
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::assembler::assemble;
+    use crate::cfg::{build, EdgeKind};
+    use crate::layout::MemoryLayout;
+    use crate::linker::link;
+
+    #[test]
+    fn test_blocks_loops_and_reachability() {
+        let source = "
+            li  r1, 3
+            li  r2, 1
+        loop:
+            sub r1, r1, r2
+            bne loop
+            jmp end
+            li  r3, 1
+        end:
+            jmp end
+        ";
+        let image = link(&[assemble(source).unwrap()], &MemoryLayout::default()).unwrap();
+        let labels = HashMap::from([(2, "loop".to_string()), (6, "end".to_string())]);
+        let cfg = build(&image.text, 0, &labels);
+
+        let names = cfg.blocks.iter().map(|block| block.name.as_str()).collect::<Vec<&str>>();
+        assert_eq!(names, ["L0000", "loop", "L0004", "L0005", "end"]);
+
+        let reachable = cfg.blocks.iter().map(|block| block.reachable).collect::<Vec<bool>>();
+        assert_eq!(reachable, [true, true, true, false, true]);
+
+        let loop_block = &cfg.blocks[1];
+        assert!(loop_block.loop_header);
+        assert_eq!((loop_block.succs[0].to, loop_block.succs[0].kind, loop_block.succs[0].back), (2, EdgeKind::Taken, true));
+        assert!(!loop_block.succs[1].back);
+
+        // jmp end to itself is the halt idiom, still a loop
+        assert!(cfg.blocks[4].loop_header);
+
+        let dot = cfg.to_dot();
+        assert!(dot.contains("\"loop\" -> \"loop\" [label=\"taken\", color=blue];"));
+        assert!(dot.contains("\"L0005\" [label=\"L0005:\\l0005  li r3, 1\\l\", style=dashed, color=gray];"));
+
+        assert!(cfg.to_json().contains("\"name\": \"L0005\", \"start\": 5, \"end\": 6, \"reachable\": false"));
+    }
+
+    #[test]
+    fn test_call_graph() {
+        let source = "
+            li   r6, back
+            jmp  square
+        back:
+            li   r1, square
+            jalr r6, r1
+        end:
+            jmp  end
+        square:
+            li   r1, double
+            jalr r7, r1
+            li   r1, 0
+            add  r1, r1, r1
+            jalr r7, r1
+            jmp  r6
+        double:
+            add  r2, r2, r2
+            jmp  r7
+        ";
+        let image = link(&[assemble(source).unwrap()], &MemoryLayout::default()).unwrap();
+        let labels = HashMap::from([
+            (2, "back".to_string()), (4, "end".to_string()), (5, "square".to_string()), (11, "double".to_string()),
+        ]);
+        let graph = build(&image.text, 0, &labels).call_graph();
+
+        // jmp end has no return address and the last jalr goes to a computed
+        // address, so neither is a call
+        assert_eq!(graph.functions, [(0, "L0000".to_string()), (5, "square".to_string()), (11, "double".to_string())]);
+        assert_eq!(graph.calls, [(0, 5), (5, 11)]);
+
+        let dot = graph.to_dot();
+        assert!(dot.contains("    \"L0000\" -> \"square\";\n    \"square\" -> \"double\";\n"));
+        assert!(graph.to_json().contains("\"calls\": [{\"caller\": 0, \"callee\": 5}, {\"caller\": 5, \"callee\": 11}]"));
+    }
+
+    // The return from a jalr call lands after it
+    #[test]
+    fn test_call_and_return() {
+        let source = "
+            li   r1, double
+            jalr r6, r1
+        end:
+            jmp  end
+        double:
+            add  r2, r2, r2
+            jmp  r6
+        ";
+        let image = link(&[assemble(source).unwrap()], &MemoryLayout::default()).unwrap();
+        let labels = HashMap::from([(2, "end".to_string()), (3, "double".to_string())]);
+        let cfg = build(&image.text, 0, &labels);
+
+        let call = cfg.blocks[0].succs.iter().map(|edge| (edge.to, edge.kind)).collect::<Vec<(u32, EdgeKind)>>();
+        assert_eq!(call, [(2, EdgeKind::Indirect), (3, EdgeKind::Indirect)]);
+        assert!(cfg.blocks.iter().all(|block| block.reachable));
+    }
+
+    #[test]
+    fn test_empty_program() {
+        let cfg = build(&[], 0, &HashMap::new());
+
+        assert!(cfg.blocks.is_empty());
+        assert_eq!(cfg.to_dot(), "digraph cfg {\n    node [shape=box, fontname=monospace];\n}\n");
+        assert_eq!(cfg.to_json(), "{\"blocks\": [\n\n]}\n");
+        assert!(cfg.call_graph().functions.is_empty());
+    }
+}
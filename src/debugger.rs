@@ -0,0 +1,401 @@
+use std::cell::RefCell;
+use std::error::Error;
+use std::io::{self, BufRead, Write};
+
+use crate::bytecode::Instruction;
+use crate::disasm::disassemble;
+use crate::isa;
+use crate::layout::section_name;
+use crate::linker::Image;
+use crate::parser::{parse_u32, Segment};
+use crate::simulator::{Machine, SimError, StopReason};
+
+const HELP: &str = "\
+step [n]            execute n instructions (s)
+next                execute until the source line changes, over jalr calls (n)
+continue            run until a breakpoint, watchpoint or the program stops (c)
+break <loc>         stop before executing a label or address (b)
+watch <loc>         stop when the word at a label or address changes
+delete <n>          remove breakpoint n, see 'info breakpoints'
+unwatch <n>         remove watchpoint n, see 'info watchpoints'
+info <what>         show registers, breakpoints or watchpoints
+print <reg>         show a register, 'pc' or 'flags' (p)
+x <loc> [n]         show n data words starting at a label or address
+disas [n]           disassemble n instructions either side of the pc
+io <device> <val>   set a switch or button, e.g. 'io btnC 1'
+quit                leave the debugger (q)";
+
+enum Stop {
+    Breakpoint(u32),
+    Watchpoint(u32, u32, u32),
+    Machine(StopReason),
+}
+
+pub struct Debugger {
+    pub machine: Machine,
+    image: Image,
+    source: Vec<String>,
+    breakpoints: Vec<u32>,
+    // Address and the value last seen there
+    watchpoints: Vec<(u32, u32)>,
+    // Steps a single continue may take before giving up
+    max_steps: u64,
+}
+
+impl Debugger {
+    pub fn new(machine: Machine, image: Image, source: &str, max_steps: u64) -> Debugger {
+        Debugger {
+            machine,
+            image,
+            source: source.lines().map(String::from).collect(),
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            max_steps,
+        }
+    }
+
+    /// Resolve a label or a number to an address in the given section
+    fn location(&self, loc: &str, section: Segment) -> Result<u32, Box<dyn Error>> {
+        if let Some(addr) = parse_u32(loc) {
+            return Ok(addr);
+        }
+
+        match self.image.label(loc) {
+            Some((sec, addr)) if sec == section => Ok(addr),
+            Some(_) => Err(format!("'{}' is not in {}", loc, section_name(section)).into()),
+            None => Err(format!("No label named '{}'", loc).into()),
+        }
+    }
+
+    /// The closest text label at or before the address, with the offset from it
+    fn symbolize(&self, addr: u32) -> Option<String> {
+        self.image.labels.iter()
+            .filter(|(_, section, a)| *section == Segment::Text && *a <= addr)
+            .max_by_key(|(_, _, a)| *a)
+            .map(|(name, _, a)| match addr - a {
+                0 => format!("<{}>", name),
+                off => format!("<{}+{}>", name, off),
+            })
+    }
+
+    fn show_location(&self) {
+        let addr = self.machine.pc;
+        let label = self.symbolize(addr).unwrap_or_default();
+
+        match self.image.line_at(addr) {
+            Some(line) => {
+                let text = self.source.get(line as usize - 1).map_or("", |text| text.trim());
+                println!("{:#06x} {}  line {}: {}", addr, label, line, text);
+            },
+            None => println!("{:#06x} {}", addr, label),
+        }
+    }
+
+    fn show_stop(&self, stop: Stop) {
+        match stop {
+            Stop::Breakpoint(addr) => println!("Breakpoint at {:#06x}", addr),
+            Stop::Watchpoint(addr, old, new) =>
+                println!("Watchpoint {:#x}: {:#010x} -> {:#010x}", addr, old, new),
+            Stop::Machine(reason) => println!("Stopped: {}", reason),
+        }
+
+        self.show_location();
+    }
+
+    fn step_one(&mut self) -> Result<Option<Stop>, SimError> {
+        if let Some(reason) = self.machine.step()? {
+            return Ok(Some(Stop::Machine(reason)));
+        }
+
+        for (addr, last) in self.watchpoints.iter_mut() {
+            let value = self.machine.load_word(*addr)?;
+
+            if value != *last {
+                let old = *last;
+                *last = value;
+
+                return Ok(Some(Stop::Watchpoint(*addr, old, value)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Step until the condition holds, a breakpoint is reached or the machine stops
+    fn run_until(&mut self, done: impl Fn(&Debugger) -> bool) -> Result<Option<Stop>, SimError> {
+        let start = self.machine.steps;
+
+        while self.machine.steps - start < self.max_steps {
+            if let Some(stop) = self.step_one()? {
+                return Ok(Some(stop));
+            }
+
+            if self.breakpoints.contains(&self.machine.pc) {
+                return Ok(Some(Stop::Breakpoint(self.machine.pc)));
+            }
+
+            if done(self) {
+                return Ok(None);
+            }
+        }
+
+        Ok(Some(Stop::Machine(StopReason::StepLimit)))
+    }
+
+    /// Step until the source line changes. A call made with jalr on the way
+    /// runs until it returns, even through other lines.
+    fn next_line(&mut self) -> Result<Option<Stop>, SimError> {
+        let line = self.image.line_at(self.machine.pc);
+        // Return addresses of the calls still running
+        let returns = RefCell::new(Vec::<u32>::new());
+
+        self.run_until(|dbg| {
+            let mut returns = returns.borrow_mut();
+            let last = dbg.machine.last;
+
+            if let Ok(Instruction::JumpLink(..)) = isa::current().decode(last.insn) {
+                returns.push(last.pc + 1);
+            } else if returns.last() == Some(&dbg.machine.pc) {
+                returns.pop();
+            }
+
+            returns.is_empty() && dbg.image.line_at(dbg.machine.pc) != line
+        })
+    }
+
+    fn disassemble_around(&self, count: u32) {
+        let start = self.machine.pc.saturating_sub(count).max(self.machine.text_base);
+
+        let words = (start..=self.machine.pc.saturating_add(count))
+            .map_while(|addr| self.machine.fetch(addr))
+            .collect::<Vec<u16>>();
+
+        for item in disassemble(&words, start) {
+            let marker = if item.addr == self.machine.pc { "=>" } else { "  " };
+            println!("{} {:#06x}: {:04x}  {}", marker, item.addr, item.word, item.text);
+        }
+    }
+
+    /// Run one command, returning false when the user asks to quit
+    pub fn execute(&mut self, line: &str) -> Result<bool, Box<dyn Error>> {
+        let words = line.split_whitespace().collect::<Vec<&str>>();
+        let Some((cmd, args)) = words.split_first() else {
+            return Ok(true);
+        };
+
+        match (*cmd, args) {
+            ("step" | "s", args) => {
+                let count = args.first().and_then(|n| parse_u32(n)).unwrap_or(1);
+
+                let mut stop = None;
+                for _ in 0..count {
+                    stop = self.step_one()?;
+                    if stop.is_some() {
+                        break;
+                    }
+                }
+
+                match stop {
+                    Some(stop) => self.show_stop(stop),
+                    None => self.show_location(),
+                }
+            },
+            ("next" | "n", []) => {
+                match self.next_line()? {
+                    Some(stop) => self.show_stop(stop),
+                    None => self.show_location(),
+                }
+            },
+            ("continue" | "c", []) => {
+                if let Some(stop) = self.run_until(|_| false)? {
+                    self.show_stop(stop);
+                }
+            },
+            ("break" | "b", [loc]) => {
+                let addr = self.location(loc, Segment::Text)?;
+                self.breakpoints.push(addr);
+                println!("Breakpoint {} at {:#06x}", self.breakpoints.len() - 1, addr);
+            },
+            ("watch", [loc]) => {
+                let addr = self.location(loc, Segment::Data)?;
+                let value = self.machine.load_word(addr)?;
+                self.watchpoints.push((addr, value));
+                println!("Watchpoint {} at {:#x}", self.watchpoints.len() - 1, addr);
+            },
+            ("delete" | "d", [n]) => {
+                let n = parse_u32(n).map(|n| n as usize)
+                    .filter(|n| *n < self.breakpoints.len())
+                    .ok_or_else(|| format!("No breakpoint {}", n))?;
+                self.breakpoints.remove(n);
+            },
+            ("unwatch", [n]) => {
+                let n = parse_u32(n).map(|n| n as usize)
+                    .filter(|n| *n < self.watchpoints.len())
+                    .ok_or_else(|| format!("No watchpoint {}", n))?;
+                self.watchpoints.remove(n);
+            },
+            ("info" | "i", ["registers" | "r"]) => print!("{}", self.machine.dump_registers()),
+            ("info" | "i", ["breakpoints" | "b"]) => {
+                for (i, addr) in self.breakpoints.iter().enumerate() {
+                    println!("{}: {:#06x} {}", i, addr, self.symbolize(*addr).unwrap_or_default());
+                }
+            },
+            ("info" | "i", ["watchpoints" | "w"]) => {
+                for (i, (addr, value)) in self.watchpoints.iter().enumerate() {
+                    println!("{}: {:#x} = {:#010x}", i, addr, value);
+                }
+            },
+            ("print" | "p", ["pc"]) => println!("pc = {:#06x}", self.machine.pc),
+            ("print" | "p", ["flags"]) =>
+                println!("zero = {}, negative = {}", self.machine.flags.zero, self.machine.flags.negative),
+            ("print" | "p", [reg]) => {
+                let idx = reg.strip_prefix('r')
+                    .and_then(|n| n.parse::<usize>().ok())
+                    .filter(|n| *n < self.machine.regs.len())
+                    .ok_or_else(|| format!("Invalid register '{}'", reg))?;
+                let value = self.machine.regs[idx];
+                println!("{} = {:#010x} ({})", reg, value, value as i32);
+            },
+            ("x", [loc, rest @ ..]) => {
+                let addr = self.location(loc, Segment::Data)?;
+                let count = rest.first().and_then(|n| parse_u32(n)).unwrap_or(1);
+
+                for i in 0..count {
+                    let word_addr = i.checked_mul(4).and_then(|off| addr.checked_add(off))
+                        .ok_or_else(|| format!("Word {} from {:#x} is past the end of the address space", i, addr))?;
+
+                    if i % 4 == 0 {
+                        print!("{:#010x}:", word_addr);
+                    }
+
+                    print!(" {:#010x}", self.machine.load_word(word_addr)?);
+
+                    if i % 4 == 3 || i == count - 1 {
+                        println!();
+                    }
+                }
+            },
+            ("disas", args) => {
+                let count = args.first().and_then(|n| parse_u32(n)).unwrap_or(4);
+                self.disassemble_around(count);
+            },
+            ("io", [device, value]) => {
+                let value = parse_u32(value).ok_or_else(|| format!("Invalid value '{}'", value))?;
+                self.machine.set_input(device, value)?;
+            },
+            ("help" | "h", []) => println!("{}", HELP),
+            ("quit" | "q", []) => return Ok(false),
+            _ => return Err(format!("Unknown command '{}', try 'help'", line.trim()).into()),
+        }
+
+        Ok(true)
+    }
+
+    /// Read commands from stdin until quit or end of input.
+    /// An empty line repeats the previous command.
+    pub fn repl(&mut self) -> Result<(), Box<dyn Error>> {
+        let stdin = io::stdin();
+        let mut last = String::new();
+
+        self.show_location();
+
+        loop {
+            print!("(sasm) ");
+            io::stdout().flush()?;
+
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+
+            if line.trim().is_empty() {
+                line = last.clone();
+            }
+
+            match self.execute(&line) {
+                Ok(true) => (),
+                Ok(false) => return Ok(()),
+                Err(e) => println!("{}", e),
+            }
+
+            last = line;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble;
+    use crate::debugger::Debugger;
+    use crate::layout::MemoryLayout;
+    use crate::linker::link;
+    use crate::simulator::{Machine, DEFAULT_MEMORY_SIZE};
+
+    #[test]
+    fn test_breakpoints_and_watchpoints() {
+        let source = "
+            li  r1, 3
+            li  r2, 1
+            li  r3, 0x20
+        loop:
+            sub r1, r1, r2
+            sw  r1, r3, 0
+            bne loop
+        ";
+        let image = link(&[assemble(source).unwrap()], &MemoryLayout::default()).unwrap();
+        let machine = Machine::new(&image, DEFAULT_MEMORY_SIZE).unwrap();
+        let mut dbg = Debugger::new(machine, image, source, 1000);
+
+        dbg.execute("break loop").unwrap();
+        dbg.execute("continue").unwrap();
+        assert_eq!(dbg.machine.pc, 3);
+
+        dbg.execute("delete 0").unwrap();
+        dbg.execute("watch 0x20").unwrap();
+        dbg.execute("continue").unwrap();
+        assert_eq!(dbg.machine.load_word(0x20).unwrap(), 2);
+        assert_eq!(dbg.machine.pc, 5);
+
+        // The branch is taken, so the next line is back at the top of the loop
+        dbg.execute("next").unwrap();
+        assert_eq!(dbg.machine.pc, 3);
+
+        dbg.execute("unwatch 0").unwrap();
+        assert!(dbg.execute("unwatch 0").is_err());
+        dbg.execute("continue").unwrap();
+        assert_eq!(dbg.machine.load_word(0x20).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_next_steps_over_calls() {
+        let source = "
+            li   r1, double
+            jalr r6, r1
+            li   r3, 1
+        end:
+            jmp  end
+        double:
+            add  r2, r2, r2
+            jmp  r6
+        ";
+        let image = link(&[assemble(source).unwrap()], &MemoryLayout::default()).unwrap();
+        let machine = Machine::new(&image, DEFAULT_MEMORY_SIZE).unwrap();
+        let mut dbg = Debugger::new(machine, image, source, 1000);
+
+        dbg.execute("next").unwrap();
+        assert_eq!(dbg.machine.pc, 1);
+        dbg.execute("next").unwrap();
+        assert_eq!(dbg.machine.pc, 2);
+        assert_eq!(dbg.machine.regs[6], 2);
+    }
+
+    #[test]
+    fn test_examine_past_the_end() {
+        let image = link(&[assemble("end:\njmp end\n").unwrap()], &MemoryLayout::default()).unwrap();
+        let machine = Machine::new(&image, DEFAULT_MEMORY_SIZE).unwrap();
+        let mut dbg = Debugger::new(machine, image, "", 1000);
+
+        assert!(dbg.execute("x 0xfffffffc 2").is_err());
+        dbg.execute("disas 0xffffffff").unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+use std::error::Error;
+use std::io::{self, BufRead};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use crate::parser::parse_u32;
+
+pub const BUS_BASE: u32 = 0x10000;
+
+/// A memory-mapped peripheral of 32-bit registers, which byte loads and
+/// stores access a part of
+pub trait Device {
+    fn name(&self) -> &str;
+
+    fn size(&self) -> u32 {
+        4
+    }
+
+    fn read(&self, offset: u32) -> u32;
+    fn write(&mut self, offset: u32, value: u32);
+
+    /// Drive an input, e.g. from an I/O script. Output-only devices return false
+    fn set_input(&mut self, _value: u32) -> bool {
+        false
+    }
+}
+
+pub struct MappedDevice {
+    pub base: u32,
+    pub device: Box<dyn Device>,
+}
+
+impl MappedDevice {
+    pub fn contains(&self, addr: u32) -> bool {
+        addr >= self.base && addr - self.base < self.device.size()
+    }
+}
+
+/// The 7-segment display, prints its value whenever it changes
+#[derive(Default)]
+pub struct SevenSegment {
+    value: u32,
+    quiet: bool,
+}
+
+impl SevenSegment {
+    /// A display that keeps its value without printing it
+    pub fn quiet() -> SevenSegment {
+        SevenSegment { value: 0, quiet: true }
+    }
+}
+
+impl Device for SevenSegment {
+    fn name(&self) -> &str {
+        "seg7"
+    }
+
+    fn read(&self, _offset: u32) -> u32 {
+        self.value
+    }
+
+    fn write(&mut self, _offset: u32, value: u32) {
+        if value != self.value && !self.quiet {
+            println!("seg7: {:#06x}", value);
+        }
+
+        self.value = value;
+    }
+}
+
+/// A read-only input such as the switches or a button
+pub struct Input {
+    name: String,
+    value: u32,
+}
+
+impl Input {
+    pub fn new(name: &str) -> Input {
+        Input { name: name.into(), value: 0 }
+    }
+}
+
+impl Device for Input {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(&self, _offset: u32) -> u32 {
+        self.value
+    }
+
+    fn write(&mut self, _offset: u32, _value: u32) {}
+
+    fn set_input(&mut self, value: u32) -> bool {
+        self.value = value;
+        true
+    }
+}
+
+/// The peripherals on the board's bus, see the memory map in test.asm
+pub fn board_devices() -> Vec<MappedDevice> {
+    let mut devices = vec![
+        MappedDevice { base: BUS_BASE, device: Box::new(SevenSegment::default()) },
+        MappedDevice { base: BUS_BASE + 0x4, device: Box::new(Input::new("switches")) },
+    ];
+
+    for (i, button) in ["btnC", "btnL", "btnR", "btnU", "btnD"].iter().enumerate() {
+        devices.push(MappedDevice {
+            base: BUS_BASE + 0x8 + 4 * i as u32,
+            device: Box::new(Input::new(button)),
+        });
+    }
+
+    devices
+}
+
+/// Set an input device to a value before the given step executes
+#[derive(Debug, Clone)]
+pub struct IoEvent {
+    pub step: u64,
+    pub device: String,
+    pub value: u32,
+}
+
+fn parse_value(value: &str) -> Result<u32, Box<dyn Error>> {
+    parse_u32(value).ok_or_else(|| format!("Invalid value: '{}'", value).into())
+}
+
+/// Parse `<device> <value>`, as typed on the terminal
+pub fn parse_io_command(line: &str) -> Result<(String, u32), Box<dyn Error>> {
+    let [device, value] = line.split_whitespace().collect::<Vec<&str>>()[..] else {
+        return Err(format!("Expected '<device> <value>', got '{}'", line.trim()).into());
+    };
+
+    Ok((device.into(), parse_value(value)?))
+}
+
+/// Parse an I/O script, one `<step> <device> <value>` per line with
+/// `#` comments, e.g. `100 btnC 1` presses the centre button at step 100.
+pub fn parse_io_script(input: &str) -> Result<Vec<IoEvent>, Box<dyn Error>> {
+    let mut events = Vec::<IoEvent>::new();
+
+    for (i, line) in input.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (step, command) = line.split_once(char::is_whitespace)
+            .ok_or_else(|| format!("line {}: expected '<step> <device> <value>'", i + 1))?;
+        let step = step.parse::<u64>()
+            .map_err(|_| format!("line {}: invalid step '{}'", i + 1, step))?;
+        let (device, value) = parse_io_command(command)
+            .map_err(|e| format!("line {}: {}", i + 1, e))?;
+
+        events.push(IoEvent { step, device, value });
+    }
+
+    events.sort_by_key(|event| event.step);
+    Ok(events)
+}
+
+/// Read `<device> <value>` commands from stdin on a background thread so
+/// inputs can be changed while the program runs.
+pub fn spawn_terminal_input() -> Receiver<(String, u32)> {
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+
+            match parse_io_command(&line) {
+                Ok(command) => {
+                    if tx.send(command).is_err() {
+                        break;
+                    }
+                },
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+    });
+
+    rx
+}
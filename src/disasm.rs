@@ -0,0 +1,274 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::bytecode::{decode, format_instruction, Condition, Instruction, Opcode};
+use crate::isa::{self, Field, Operation};
+use crate::parser::parse_u32;
+
+/// One word of disassembled code, or several if an idiom was recognised
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisasmItem {
+    pub addr: u32,
+    pub word: u16,
+    // Number of words, more than one for push and pop
+    pub len: u32,
+    // None if the word isn't a valid instruction, the first one for idioms
+    pub insn: Option<Instruction>,
+    pub text: String,
+    // Where a branch or immediate jump goes
+    pub target: Option<u32>,
+}
+
+/// The text of a single word, `.word 0x....` if it isn't an instruction
+pub fn format_word(word: u16) -> String {
+    format_instruction(word).unwrap_or_else(|_| format!(".word {:#06x}", word))
+}
+
+/// The branch offset field of an instruction
+fn offset_field(cond: Condition) -> Option<&'static Field> {
+    isa::current().def_for(Operation::Branch(cond))?.field("off")
+}
+
+/// Destination of a branch or immediate jump at `addr`, matching the simulator.
+/// Like `addr`, it counts instructions whatever the addressing model.
+fn branch_target(addr: u32, insn: Instruction) -> Option<u32> {
+    let addressing = isa::current().addressing;
+
+    match insn {
+        Instruction::Branch(cond, off) => {
+            let off = offset_field(cond)?.value(off);
+            let step = addressing.text_step() as i32;
+            (off % step == 0).then(|| (addr as i32 + 1 + off / step) as u32)
+        },
+        Instruction::Jump(target) => addressing.text_index(target as u32),
+        _ => None,
+    }
+}
+
+/// Disassemble instruction words loaded at `base`. Words that don't decode
+/// are rendered as `.word 0x....` data.
+pub fn disassemble(words: &[u16], base: u32) -> Vec<DisasmItem> {
+    words.iter().enumerate().map(|(i, word)| {
+        let addr = base + i as u32;
+        let insn = decode(*word).ok();
+        let target = insn.and_then(|insn| branch_target(addr, insn));
+
+        // Branches show where they go rather than the offset
+        let text = match (insn, target) {
+            (Some(insn @ Instruction::Branch(..)), Some(target)) =>
+                format!("{} {:#06x}", isa::current().def_for(insn.operation()).unwrap().mnemonic, target),
+            _ => format_word(*word),
+        };
+
+        DisasmItem { addr, word: *word, len: 1, insn, text, target }
+    }).collect()
+}
+
+/// Split a raw big-endian binary into instruction words
+pub fn words_from_bytes(bytes: &[u8]) -> Result<Vec<u16>, String> {
+    let size = isa::current().addressing.insn_bytes as usize;
+    if !bytes.len().is_multiple_of(size) {
+        return Err(format!("Bytecode length is not a multiple of {}", size));
+    }
+
+    Ok(bytes.chunks(size).map(|b| b.iter().fold(0, |word, b| word << 8 | *b as u32) as u16).collect())
+}
+
+/// Parse a symbol map, one `<address> <name>` per line with `#` comments
+pub fn parse_symbol_map(input: &str) -> Result<HashMap<u32, String>, String> {
+    let mut symbols = HashMap::<u32, String>::new();
+
+    for (i, line) in input.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let [addr, name] = line.split_whitespace().collect::<Vec<&str>>()[..] else {
+            return Err(format!("line {}: expected '<address> <name>'", i + 1));
+        };
+
+        let addr = parse_u32(addr).ok_or_else(|| format!("line {}: invalid address '{}'", i + 1, addr))?;
+
+        symbols.insert(addr, name.into());
+    }
+
+    Ok(symbols)
+}
+
+/// The idiom starting at `items[0]`, and the number of items it covers
+fn idiom(items: &[DisasmItem]) -> Option<(String, usize)> {
+    let insns = items.iter().take(3).map(|item| item.insn).collect::<Option<Vec<Instruction>>>();
+    // Only pseudo-instructions the assembler has, cmp is real in the extension
+    let pseudo = |name| isa::current().pseudo(name).is_some();
+
+    let data_word = isa::current().addressing.data_word() as u16;
+
+    // The push and pop expansions in Isa::builtin
+    match insns.as_deref() {
+        Some([Instruction::Li(7, size), Instruction::Alu(Opcode::Sub, 15, 15, 7), Instruction::Mem(Opcode::Sw, reg, 15, 0), ..]) if *size == data_word && pseudo("push") =>
+            return Some((format!("push r{}", reg), 3)),
+        Some([Instruction::Mem(Opcode::Lw, reg, 15, 0), Instruction::Li(7, size), Instruction::Alu(Opcode::Add, 15, 15, 7), ..]) if *size == data_word && pseudo("pop") =>
+            return Some((format!("pop r{}", reg), 3)),
+        _ => ()
+    }
+
+    match items.first()?.insn? {
+        Instruction::Alu(Opcode::Sub, 0, rt, rs) if pseudo("cmp") => Some((format!("cmp r{}, r{}", rt, rs), 1)),
+        _ => None,
+    }
+}
+
+/// Show `sub r0, rX, rY` as `cmp rX, rY` and merge the push and pop
+/// expansions into one item. Idioms never swallow an address for which
+/// `keep` is true, so labels there stay visible.
+pub fn recognise_idioms(items: &[DisasmItem], keep: impl Fn(u32) -> bool) -> Vec<DisasmItem> {
+    let mut result = Vec::<DisasmItem>::new();
+    let mut i = 0;
+
+    while i < items.len() {
+        let mut item = items[i].clone();
+
+        let found = idiom(&items[i..])
+            .filter(|(_, len)| !items[i + 1..i + len].iter().any(|inner| keep(inner.addr)));
+        if let Some((text, len)) = found {
+            item.text = text;
+            item.len = len as u32;
+        }
+
+        i += item.len as usize;
+        result.push(item);
+    }
+
+    result
+}
+
+/// An item as sasm source, with branch and jump targets replaced by labels
+fn source_text(item: &DisasmItem, labels: &BTreeMap<u32, String>) -> String {
+    let target = || item.target.and_then(|target| labels.get(&target).cloned());
+
+    let mnemonic = |insn: Instruction| &isa::current().def_for(insn.operation()).unwrap().mnemonic;
+
+    match item.insn {
+        // Without a label, the offset as the assembler reads it
+        Some(insn @ Instruction::Branch(cond, off)) =>
+            format!("{} {}", mnemonic(insn), target().unwrap_or(offset_field(cond).unwrap().value(off).to_string())),
+        Some(insn @ Instruction::Jump(addr)) =>
+            format!("{} {}", mnemonic(insn), target().unwrap_or(addr.to_string())),
+        // The assembler always encodes not with rs = 0
+        Some(Instruction::Alu(Opcode::Not, _, _, rs)) if rs != 0 =>
+            format!(".word {:#06x}", item.word),
+        _ => item.text.clone(),
+    }
+}
+
+/// Disassemble a program into source that sasm assembles back to the same
+/// words. Targets of branches and jumps get a label, named from `symbols`
+/// where it has an entry for the address and `L<address>` otherwise.
+/// With `idioms`, see `recognise_idioms`.
+pub fn disassemble_program(words: &[u16], base: u32, symbols: &HashMap<u32, String>, idioms: bool) -> String {
+    let mut items = disassemble(words, base);
+    let end = base + words.len() as u32;
+
+    let mut labels = items.iter()
+        .filter_map(|item| item.target)
+        .filter(|target| (base..=end).contains(target))
+        .map(|target| (target, format!("L{:04x}", target)))
+        .collect::<BTreeMap<u32, String>>();
+
+    for (addr, name) in symbols {
+        if (base..=end).contains(addr) {
+            labels.insert(*addr, name.clone());
+        }
+    }
+
+    if idioms {
+        items = recognise_idioms(&items, |addr| labels.contains_key(&addr));
+    }
+
+    let mut out = String::new();
+    for item in &items {
+        if let Some(label) = labels.get(&item.addr) {
+            out += &format!("{}:\n", label);
+        }
+
+        out += &format!("    {}\n", source_text(item, &labels));
+    }
+
+    if let Some(label) = labels.get(&end) {
+        out += &format!("{}:\n", label);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::assembler::assemble;
+    use crate::bytecode::{Instruction, Opcode};
+    use crate::disasm::{disassemble, disassemble_program, words_from_bytes};
+    use crate::layout::MemoryLayout;
+    use crate::linker::link;
+
+    fn assemble_text(source: &str) -> Vec<u16> {
+        link(&[assemble(source).unwrap()], &MemoryLayout::default()).unwrap().text
+    }
+
+    #[test]
+    fn test_disassemble_items() {
+        let words = words_from_bytes(&[0xa1, 0x05, 0xff, 0xff, 0x02, 0x12]).unwrap();
+        let items = disassemble(&words, 0x10);
+
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].insn, Some(Instruction::Li(1, 5)));
+        assert_eq!(items[0].text, "li r1, 5");
+
+        // Reserved bits are set, so this isn't an instruction
+        assert_eq!((items[1].addr, items[1].insn, items[1].text.as_str()), (0x11, None, ".word 0xffff"));
+        assert_eq!(items[2].insn, Some(Instruction::Alu(Opcode::Add, 2, 1, 2)));
+
+        assert!(words_from_bytes(&[0xa1]).is_err());
+    }
+
+    #[test]
+    fn test_reassemble() {
+        let source = "
+            li  r1, 3
+        loop:
+            sub r1, r1, r2
+            bne loop
+            blt end
+            jmp loop
+            not r3, r4
+            .word 0xffff
+        end:
+        ";
+        let words = assemble_text(source);
+
+        let symbols = HashMap::from([(1, "top".to_string())]);
+        let output = disassemble_program(&words, 0, &symbols, false);
+
+        assert!(output.starts_with("    li r1, 3\ntop:\n    sub r1, r1, r2\n    bne top\n    blt L0007\n    jmp top\n"));
+        assert!(output.ends_with("    not r3, r4\n    .word 0xffff\nL0007:\n"));
+        assert_eq!(assemble_text(&output), words);
+    }
+
+    #[test]
+    fn test_idioms() {
+        let source = "
+            push r1
+            cmp r2, r3
+            pop r4
+            push r5
+        ";
+        let words = assemble_text(source);
+        let symbols = HashMap::from([(9, "split".to_string())]);
+        let output = disassemble_program(&words, 0, &symbols, true);
+
+        // The last push is interrupted by a label so it stays as three words
+        assert_eq!(output, "    push r1\n    cmp r2, r3\n    pop r4\n    li r7, 4\n    sub r15, r15, r7\n\
+                            split:\n    sw r5, r15, 0\n");
+        assert_eq!(assemble_text(&output), words);
+    }
+}
@@ -0,0 +1,470 @@
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::simulator::{Machine, SimError, StopReason};
+
+/// GDB sees one address space, so instruction memory is mapped above the
+/// data memory with each 16-bit word taking two bytes. The pc register is
+/// reported as an address in this window.
+pub const TEXT_WINDOW: u32 = 0x8000_0000;
+
+// r0-r15 followed by the pc
+const NUM_REGS: usize = 17;
+
+// How many steps to run between checks for a Ctrl-C from GDB
+const INTERRUPT_POLL: u64 = 1024;
+
+fn target_xml() -> String {
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\"?>",
+        "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">",
+        "<target version=\"1.0\"><feature name=\"org.sasm.cpu\">"
+    ));
+
+    for i in 0..16 {
+        xml += &format!("<reg name=\"r{}\" bitsize=\"32\" type=\"uint32\" regnum=\"{}\"/>", i, i);
+    }
+
+    xml += "<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"16\"/>";
+    xml += "</feature></target>";
+    xml
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex_u32(hex: &str) -> Option<u32> {
+    u32::from_str_radix(hex, 16).ok()
+}
+
+/// Parse the `addr,len` arguments of the memory and breakpoint packets
+fn parse_addr_len(args: &str) -> Option<(u32, u32)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((parse_hex_u32(addr)?, parse_hex_u32(len)?))
+}
+
+struct Connection {
+    stream: TcpStream,
+    // A byte read while checking for an interrupt that wasn't one
+    pending: Option<u8>,
+}
+
+impl Connection {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(byte) = self.pending.take() {
+            return Ok(Some(byte));
+        }
+
+        let mut byte = [0u8];
+
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    /// Wait for the next packet, acknowledging it. None when GDB disconnects
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // Skip acks and stray interrupts until the start of a packet
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => (),
+                Some(_) => continue,
+            }
+
+            let mut data = Vec::<u8>::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(b) => data.push(b),
+                }
+            }
+
+            let mut sum = [0u8; 2];
+            for byte in sum.iter_mut() {
+                *byte = self.read_byte()?.ok_or(io::ErrorKind::UnexpectedEof)?;
+            }
+
+            let data = String::from_utf8_lossy(&data).into_owned();
+            let expected = std::str::from_utf8(&sum).ok().and_then(|s| u8::from_str_radix(s, 16).ok());
+
+            if expected == Some(checksum(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(data));
+            }
+
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        write!(self.stream, "${}#{:02x}", data, checksum(data))?;
+        self.stream.flush()
+    }
+
+    /// Check for a Ctrl-C without blocking. Any other byte is kept for the
+    /// next packet.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        if self.pending.is_some() {
+            return Ok(false);
+        }
+
+        self.stream.set_nonblocking(true)?;
+
+        let mut byte = [0u8];
+        let result = match self.stream.read(&mut byte) {
+            Ok(1) if byte[0] == 0x03 => Ok(true),
+            Ok(1) => {
+                self.pending = Some(byte[0]);
+                Ok(false)
+            },
+            Ok(_) => Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        };
+
+        self.stream.set_nonblocking(false)?;
+        result
+    }
+}
+
+/// A GDB remote serial protocol stub driving the simulator
+pub struct GdbStub {
+    machine: Machine,
+    // Software breakpoints, as .text word addresses
+    breakpoints: Vec<u32>,
+}
+
+impl GdbStub {
+    pub fn new(machine: Machine) -> GdbStub {
+        GdbStub { machine, breakpoints: Vec::new() }
+    }
+
+    fn reg(&self, n: usize) -> Option<u32> {
+        match n {
+            0..16 => Some(self.machine.regs[n]),
+            16 => Some(TEXT_WINDOW + 2 * self.machine.pc),
+            _ => None,
+        }
+    }
+
+    fn set_reg(&mut self, n: usize, value: u32) -> bool {
+        match n {
+            // r0 is hardwired to zero
+            0 => true,
+            1..16 => {
+                self.machine.regs[n] = value;
+                true
+            },
+            16 if value >= TEXT_WINDOW => {
+                self.machine.pc = (value - TEXT_WINDOW) / 2;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    fn read_byte(&self, addr: u32) -> Result<u8, SimError> {
+        if addr >= TEXT_WINDOW {
+            let word = self.machine.fetch((addr - TEXT_WINDOW) / 2)
+                .ok_or_else(|| SimError(format!("no instruction at {:#x}", addr)))?;
+
+            return Ok(word.to_be_bytes()[(addr % 2) as usize]);
+        }
+
+        let word = self.machine.load_word(addr & !3)?;
+        Ok(word.to_be_bytes()[(addr % 4) as usize])
+    }
+
+    fn write_byte(&mut self, addr: u32, value: u8) -> Result<(), SimError> {
+        if addr >= TEXT_WINDOW {
+            let idx = ((addr - TEXT_WINDOW) / 2).checked_sub(self.machine.text_base)
+                .filter(|idx| (*idx as usize) < self.machine.text.len())
+                .ok_or_else(|| SimError(format!("no instruction at {:#x}", addr)))?;
+
+            let mut bytes = self.machine.text[idx as usize].to_be_bytes();
+            bytes[(addr % 2) as usize] = value;
+            self.machine.text[idx as usize] = u16::from_be_bytes(bytes);
+
+            return Ok(());
+        }
+
+        let mut bytes = self.machine.load_word(addr & !3)?.to_be_bytes();
+        bytes[(addr % 4) as usize] = value;
+        self.machine.store_word(addr & !3, u32::from_be_bytes(bytes))
+    }
+
+    /// Reply describing why the target stopped
+    fn stop_reply(&self, reason: Option<StopReason>) -> String {
+        match reason {
+            Some(StopReason::EndOfProgram) => "W00".into(),
+            _ => "S05".into(),
+        }
+    }
+
+    fn resume(&mut self, conn: &mut Connection, single_step: bool) -> Result<String, Box<dyn Error>> {
+        loop {
+            let reason = match self.machine.step() {
+                Ok(reason) => reason,
+                // Report faults to GDB as a segfault rather than dropping the connection
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return Ok("S0b".into());
+                }
+            };
+
+            if reason.is_some() || single_step || self.breakpoints.contains(&self.machine.pc) {
+                return Ok(self.stop_reply(reason));
+            }
+
+            if self.machine.steps.is_multiple_of(INTERRUPT_POLL) && conn.interrupted()? {
+                return Ok("S02".into());
+            }
+        }
+    }
+
+    fn breakpoint_addr(args: &str) -> Option<u32> {
+        let (addr, _kind) = parse_addr_len(args)?;
+        addr.checked_sub(TEXT_WINDOW).map(|addr| addr / 2)
+    }
+
+    /// Handle one packet, returning the reply or None to close the connection
+    fn handle(&mut self, conn: &mut Connection, packet: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let (cmd, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+
+        let reply = match cmd {
+            "?" => "S05".into(),
+            "g" => (0..NUM_REGS)
+                .map(|n| to_hex(&self.reg(n).unwrap().to_be_bytes()))
+                .collect(),
+            "G" => match from_hex(args) {
+                Some(bytes) if bytes.len() == 4 * NUM_REGS => {
+                    for (n, b) in bytes.chunks(4).enumerate() {
+                        self.set_reg(n, u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+                    }
+                    "OK".into()
+                },
+                _ => "E01".into(),
+            },
+            "p" => match usize::from_str_radix(args, 16).ok().and_then(|n| self.reg(n)) {
+                Some(value) => to_hex(&value.to_be_bytes()),
+                None => "E01".into(),
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(n, value)| {
+                    let bytes = from_hex(value).filter(|b| b.len() == 4)?;
+                    Some((usize::from_str_radix(n, 16).ok()?, u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])))
+                });
+
+                match parsed {
+                    Some((n, value)) if self.set_reg(n, value) => "OK".into(),
+                    _ => "E01".into(),
+                }
+            },
+            "m" => {
+                let bytes = parse_addr_len(args)
+                    .ok_or(())
+                    .and_then(|(addr, len)| {
+                        (0..len)
+                            .map(|i| {
+                                let addr = addr.checked_add(i).ok_or(())?;
+                                self.read_byte(addr).map_err(|_| ())
+                            })
+                            .collect::<Result<Vec<u8>, ()>>()
+                    });
+
+                match bytes {
+                    Ok(bytes) => to_hex(&bytes),
+                    Err(()) => "E01".into(),
+                }
+            },
+            "M" => {
+                let parsed = args.split_once(':')
+                    .and_then(|(range, data)| Some((parse_addr_len(range)?, from_hex(data)?)));
+
+                match parsed {
+                    Some(((addr, len), data)) if data.len() == len as usize => {
+                        let written = data.iter().enumerate()
+                            .try_for_each(|(i, b)| {
+                                let addr = addr.checked_add(i as u32).ok_or(())?;
+                                self.write_byte(addr, *b).map_err(|_| ())
+                            });
+
+                        if written.is_ok() { "OK".into() } else { "E01".into() }
+                    },
+                    _ => "E01".into(),
+                }
+            },
+            "s" => self.resume(conn, true)?,
+            "c" => self.resume(conn, false)?,
+            "Z" | "z" if args.starts_with("0,") => match GdbStub::breakpoint_addr(&args[2..]) {
+                Some(addr) => {
+                    self.breakpoints.retain(|bp| *bp != addr);
+                    if cmd == "Z" {
+                        self.breakpoints.push(addr);
+                    }
+                    "OK".into()
+                },
+                None => "E01".into(),
+            },
+            "H" => "OK".into(),
+            "k" => return Ok(None),
+            "D" => {
+                conn.send("OK")?;
+                return Ok(None);
+            },
+            "q" if args.starts_with("Supported") => "PacketSize=1000;qXfer:features:read+".into(),
+            "q" if args == "Attached" => "1".into(),
+            "q" if args == "C" => "QC1".into(),
+            "q" if args == "fThreadInfo" => "m1".into(),
+            "q" if args == "sThreadInfo" => "l".into(),
+            "q" if args.starts_with("Xfer:features:read:target.xml:") => {
+                let xml = target_xml();
+
+                match parse_addr_len(&args["Xfer:features:read:target.xml:".len()..]) {
+                    Some((offset, len)) => {
+                        let start = (offset as usize).min(xml.len());
+                        let end = (start + len as usize).min(xml.len());
+                        let more = if end < xml.len() { "m" } else { "l" };
+
+                        format!("{}{}", more, &xml[start..end])
+                    },
+                    None => "E01".into(),
+                }
+            },
+            // An empty reply tells GDB the packet is not supported
+            _ => String::new(),
+        };
+
+        Ok(Some(reply))
+    }
+
+    /// Serve a single GDB connection until it detaches or disconnects
+    pub fn serve(&mut self, listener: TcpListener) -> Result<(), Box<dyn Error>> {
+        let (stream, peer) = listener.accept()?;
+        eprintln!("gdb connected from {}", peer);
+
+        // Packets are tiny, don't let Nagle hold them back
+        stream.set_nodelay(true)?;
+
+        let mut conn = Connection { stream, pending: None };
+
+        while let Some(packet) = conn.read_packet()? {
+            match self.handle(&mut conn, &packet)? {
+                Some(reply) => conn.send(&reply)?,
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use crate::assembler::assemble;
+    use crate::gdbstub::{checksum, Connection, GdbStub};
+    use crate::layout::MemoryLayout;
+    use crate::linker::link;
+    use crate::simulator::{Machine, DEFAULT_MEMORY_SIZE};
+
+    // Send a packet and return the reply with the framing removed
+    fn request(stream: &mut TcpStream, packet: &str) -> String {
+        write!(stream, "${}#{:02x}", packet, checksum(packet)).unwrap();
+
+        let mut reply = Vec::<u8>::new();
+        let mut byte = [0u8];
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            match byte[0] {
+                b'+' if reply.is_empty() => continue,
+                b'#' => break,
+                b => reply.push(b),
+            }
+        }
+
+        let mut sum = [0u8; 2];
+        stream.read_exact(&mut sum).unwrap();
+
+        String::from_utf8(reply[1..].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_gdb_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let source = "li r1, 5\nli r2, 7\nadd r3, r1, r2\nsw r3, r0, 1\nend:\njmp end\n";
+            let image = link(&[assemble(source).unwrap()], &MemoryLayout::default()).unwrap();
+            let machine = Machine::new(&image, DEFAULT_MEMORY_SIZE).unwrap();
+
+            GdbStub::new(machine).serve(listener).unwrap();
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_nodelay(true).unwrap();
+
+        assert!(request(&mut stream, "qSupported:multiprocess+").contains("qXfer:features:read+"));
+        assert!(request(&mut stream, "qXfer:features:read:target.xml:0,1000").contains("org.sasm.cpu"));
+        assert_eq!(request(&mut stream, "?"), "S05");
+
+        // Break before the store, the fourth instruction in .text
+        assert_eq!(request(&mut stream, "Z0,80000006,2"), "OK");
+        assert_eq!(request(&mut stream, "c"), "S05");
+        assert_eq!(request(&mut stream, "p10"), "80000006");
+        assert_eq!(request(&mut stream, "p3"), "0000000c");
+
+        assert_eq!(request(&mut stream, "P3=00000010"), "OK");
+        assert_eq!(request(&mut stream, "s"), "S05");
+        assert_eq!(request(&mut stream, "m4,4"), "00000010");
+
+        assert_eq!(request(&mut stream, "M8,4:deadbeef"), "OK");
+        assert_eq!(request(&mut stream, "m8,4"), "deadbeef");
+        assert_eq!(request(&mut stream, "m80000000,2"), "a105");
+        assert_eq!(request(&mut stream, "mffffffff,2"), "E01");
+        assert_eq!(request(&mut stream, "Mffffffff,2:0000"), "E01");
+
+        assert_eq!(request(&mut stream, "D"), "OK");
+        server.join().unwrap();
+    }
+
+    // A packet that arrives while the target runs is read whole afterwards
+    #[test]
+    fn test_interrupt_check_keeps_packets() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut conn = Connection { stream: listener.accept().unwrap().0, pending: None };
+
+        client.write_all(b"$g#67").unwrap();
+        conn.stream.peek(&mut [0u8]).unwrap();
+        assert!(!conn.interrupted().unwrap());
+        assert_eq!(conn.read_packet().unwrap().as_deref(), Some("g"));
+
+        client.write_all(&[0x03]).unwrap();
+        conn.stream.peek(&mut [0u8]).unwrap();
+        assert!(conn.interrupted().unwrap());
+    }
+}
@@ -1,256 +1,163 @@
-use std::{error, fmt};
+use std::error;
 
-use crate::{bytecode::{Instruction, InvalidInstruction, Opcode, Condition}, parser::Operand};
+use crate::bytecode::{Instruction, InvalidInstruction};
+use crate::isa::{self, FieldKind, InsnDef, PseudoDef};
+use crate::parser::{parse_operand, Operand};
 
 #[derive(Debug, Clone)]
-pub struct InvalidOperands(PseudoInstruction, String);
+pub struct InvalidOperands(String, String);
 
 impl std::fmt::Display for InvalidOperands {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Invalid operand for '{:?}': '{}'.", self.0, self.1)
+        write!(f, "Invalid operand for '{}': '{}'.", self.0, self.1)
     }
 }
 
 impl error::Error for InvalidOperands{}
 
-#[derive(Debug, Clone, Copy)]
-pub enum PseudoInstruction {
-    Nop,
-    Add,
-    Sub,
-    Or,
-    And,
-    Xor,
-    Not,
-    Shl,
-    Shr,
-    Cmp,
-    Lw,
-    Sw,
-    Lb,
-    Sb,
-    Beq,
-    Bne,
-    Blt,
-    Bge,
-    Jump,
-    Li,
-    Push,
-    Pop
+/// What a mnemonic in the source refers to
+#[derive(Debug, Clone)]
+pub enum Mnemonic {
+    // Encodings to try in order, e.g. jmp takes an address or a register
+    Real(Vec<&'static InsnDef>),
+    Pseudo(&'static PseudoDef),
 }
 
-impl PseudoInstruction {
-    pub fn is_branch(&self) -> bool {
-        matches!(self, PseudoInstruction::Beq | PseudoInstruction::Bne | PseudoInstruction::Blt | PseudoInstruction::Bge)
-    }
-
-    pub fn length(&self) -> u32 {
-        // Push and pop expand to a sub/add and a load/store
-        
+impl Mnemonic {
+    pub fn name(&self) -> &str {
         match self {
-            PseudoInstruction::Push | PseudoInstruction::Pop => 3,
-            _ => 1
+            Mnemonic::Real(defs) => &defs[0].mnemonic,
+            Mnemonic::Pseudo(pseudo) => &pseudo.mnemonic,
         }
     }
 
-    pub fn to_opcode(&self) -> Opcode {
+    /// Number of words it assembles to
+    pub fn length(&self) -> u32 {
         match self {
-            PseudoInstruction::Nop => Opcode::Add, // NOP is treated as ADD with no operation
-            PseudoInstruction::Add => Opcode::Add,
-            PseudoInstruction::Sub => Opcode::Sub,
-            PseudoInstruction::Or => Opcode::Or,
-            PseudoInstruction::And => Opcode::And,
-            PseudoInstruction::Xor => Opcode::Xor,
-            PseudoInstruction::Not => Opcode::Not,
-            PseudoInstruction::Shl => Opcode::Shl,
-            PseudoInstruction::Shr => Opcode::Shr,
-            PseudoInstruction::Cmp => Opcode::Add,
-            PseudoInstruction::Lw => Opcode::Lw,
-            PseudoInstruction::Sw => Opcode::Sw,
-            PseudoInstruction::Beq => Opcode::Branch, // Branch on equal
-            PseudoInstruction::Bne => Opcode::Branch, // Branch on not equal
-            PseudoInstruction::Blt => Opcode::Branch, // Branch on less than
-            PseudoInstruction::Bge => Opcode::Branch, // Branch on greater than or equal
-            PseudoInstruction::Jump => Opcode::Jump,
-            PseudoInstruction::Li => Opcode::Li,
-            _ => panic!("Pseudo instruction does not have an opcode: {:?}", self),
+            Mnemonic::Real(_) => 1,
+            Mnemonic::Pseudo(pseudo) => pseudo.expansion.iter()
+                .map(|line| name_to_op(line.split_whitespace().next().unwrap_or_default()).map_or(1, |op| op.length()))
+                .sum(),
         }
     }
 
-    pub fn branch_condition(&self) -> Option<Condition> {
+    /// The encoding used when an operand is a label
+    pub fn label_def(&self) -> Option<&'static InsnDef> {
         match self {
-            PseudoInstruction::Beq => Some(Condition::Equal),
-            PseudoInstruction::Bne => Some(Condition::NotEqual),
-            PseudoInstruction::Blt => Some(Condition::LessThan),
-            PseudoInstruction::Bge => Some(Condition::GreaterThanEqual),
-            _ => None,
+            Mnemonic::Real(defs) =>
+                defs.iter().copied().find(|def| def.operand_fields().any(|field| field.kind == FieldKind::Imm)),
+            Mnemonic::Pseudo(_) => None,
         }
     }
 }
 
-enum OperandType {
-    Reg,
-    RegLo,
-    Imm(u8),
-}
+pub fn name_to_op(name: &str) -> Result<Mnemonic, InvalidInstruction> {
+    let isa = isa::current();
 
-pub fn name_to_op(name: &str) -> Result<PseudoInstruction, InvalidInstruction> {
-    match name {
-        "nop" => Ok(PseudoInstruction::Nop),
-        "add" => Ok(PseudoInstruction::Add),
-        "sub" => Ok(PseudoInstruction::Sub),
-        "or" => Ok(PseudoInstruction::Or),
-        "and" => Ok(PseudoInstruction::And),
-        "xor" => Ok(PseudoInstruction::Xor),
-        "not" => Ok(PseudoInstruction::Not),
-        "shl" => Ok(PseudoInstruction::Shl),
-        "shr" => Ok(PseudoInstruction::Shr),
-        "cmp" => Ok(PseudoInstruction::Cmp),
-        "lw" => Ok(PseudoInstruction::Lw),
-        "sw" => Ok(PseudoInstruction::Sw),
-        "beq" => Ok(PseudoInstruction::Beq),
-        "bne" => Ok(PseudoInstruction::Bne),
-        "blt" => Ok(PseudoInstruction::Blt),
-        "bge" => Ok(PseudoInstruction::Bge),
-        "jmp" => Ok(PseudoInstruction::Jump),
-        "li" => Ok(PseudoInstruction::Li),
-        "push" => Ok(PseudoInstruction::Push),
-        "pop" => Ok(PseudoInstruction::Pop),
-        invalid => Err(InvalidInstruction(invalid.into()))
+    let defs = isa.defs_named(name);
+    if !defs.is_empty() {
+        return Ok(Mnemonic::Real(defs));
     }
+
+    isa.pseudo(name)
+        .map(Mnemonic::Pseudo)
+        .ok_or_else(|| InvalidInstruction(name.into()))
 }
 
-fn do_convert_operands(opcode: PseudoInstruction, defn: &[OperandType], operands: &[Operand]) -> Result<Vec<u16>, InvalidOperands> {
-    if defn.len() != operands.len() {
-        return Err(
-            InvalidOperands(opcode, format!("Expected {} operands", defn.len()))
-        );
+/// Check operands against the fields of an encoding
+fn convert_operands(def: &InsnDef, operands: &[Operand]) -> Result<Instruction, InvalidOperands> {
+    let error = |message: String| InvalidOperands(def.mnemonic.clone(), message);
+
+    if def.operands.len() != operands.len() {
+        return Err(error(format!("Expected {} operands", def.operands.len())));
     }
 
-    let mut result = Vec::<u16>::new();
+    let mut values = Vec::<(&str, u16)>::new();
 
-    for (i, o) in operands.iter().enumerate() {
-        let real_op: Result<u16, String> = match &defn[i] {
-            OperandType::Reg =>
-                match o {
-                    Operand::Register(r) => Ok((*r) as u16),
-                    invalid => Err(invalid.to_string())
-                },
-            OperandType::RegLo =>
-                match o {
-                    Operand::Register(r) => {
-                        if *r < 8 {
-                            Ok(*r as u16)
-                        } else {
-                            Err(format!("Rs must be one of r0-r7"))
-                        }
-                    },
-                    invalid => Err(invalid.to_string())
-                },
-            OperandType::Imm(bits) =>
-                match o {
-                    Operand::Immediate(val) => {
-                        let unsigned = *val as u32;
-                        if unsigned >= (1 << bits) {
-                            eprintln!("Warning: truncating {} to {}-bits", val, bits);
-                        }
+    for (field, operand) in def.operand_fields().zip(operands) {
+        let value = match (field.kind, operand) {
+            (FieldKind::Reg, Operand::Register(r)) => {
+                let count = (1u32 << field.width).min(isa::current().registers);
+                if *r as u32 >= count {
+                    return Err(error(format!("{} must be one of r0-r{}", field.name, count - 1)));
+                }
 
-                        Ok((*val & ((1 << bits) - 1)) as u16)
-                    }
-                    invalid => Err(invalid.to_string())
+                *r as u16
+            },
+            (FieldKind::Imm, Operand::Immediate(val)) => {
+                if !field.holds(*val as i64) {
+                    eprintln!("Warning: truncating {} to {}-bits", val, field.width);
                 }
-        };
 
-        let op = real_op
-            .map_err(|e| InvalidOperands(opcode, e))?;
+                (*val & ((1 << field.width) - 1)) as u16
+            },
+            (_, invalid) => return Err(error(invalid.to_string())),
+        };
 
-        result.push(op);
+        values.push((field.name.as_str(), value));
     }
 
-    Ok(result.into())
+    Ok(Instruction::from_fields(def.operation, |name| {
+        values.iter().find(|(field, _)| *field == name).map(|(_, value)| *value)
+    }))
 }
 
-pub fn make_single_insn(op: PseudoInstruction, operands: &[Operand]) -> Result<Instruction, InvalidOperands> {
-    match op {
-        PseudoInstruction::Nop => Ok(Instruction::Alu(Opcode::Add, 0, 0, 0)),
-        PseudoInstruction::Add | PseudoInstruction::Sub | PseudoInstruction::And | PseudoInstruction::Or | PseudoInstruction::Xor
-                | PseudoInstruction::Shl | PseudoInstruction::Shr => {
-            let [p1, p2, p3] = do_convert_operands(op, &[OperandType::Reg, OperandType::Reg, OperandType::RegLo], operands)?[..]
-                else { unreachable!() };
-
-            Ok(Instruction::Alu(op.to_opcode(), p1, p2, p3))
-        },
-        PseudoInstruction::Not => {
-            let [p1, p2] = do_convert_operands(op, &[OperandType::Reg, OperandType::Reg], operands)?[..]
-                else { unreachable!() };
-
-            Ok(Instruction::Alu(Opcode::Not, p1, p2, 0))
-        },
-        PseudoInstruction::Cmp => {
-            let [p1, p2] = do_convert_operands(op, &[OperandType::Reg, OperandType::RegLo], operands)?[..]
-                else { unreachable!() };
-
-            Ok(Instruction::Alu(Opcode::Sub, 0, p1, p2))
-        },
-        PseudoInstruction::Lw | PseudoInstruction::Sw => {
-            let [p1, p2, p3] = do_convert_operands(op, &[OperandType::Reg, OperandType::Reg, OperandType::Imm(3)], operands)?[..]
-                else { unreachable!() };
-
-            Ok(Instruction::Mem(op.to_opcode(), p1, p2, p3))
-        },
-        PseudoInstruction::Beq | PseudoInstruction::Bne | PseudoInstruction::Bge | PseudoInstruction::Blt => {
-            let cond = op.branch_condition().unwrap();
-            let [off] = do_convert_operands(op, &[OperandType::Imm(9)], operands)?[..]
-                else { unreachable!() };
-
-            Ok(Instruction::Branch(cond, off))
-        }
-        PseudoInstruction::Jump =>
-            // Attempt both jmp <imm> and jmp <reg>
-            match do_convert_operands(op, &[OperandType::Imm(11)], operands) {
-                Ok(op) =>
-                    Ok(Instruction::Jump(op[0])),
-                Err(_) =>
-                    do_convert_operands(PseudoInstruction::Jump, &[OperandType::Reg], operands)
-                        .map(|op| Instruction::JumpReg(op[0]))
-            },
-        PseudoInstruction::Li => {
-            let [reg, val] = do_convert_operands(op, &[OperandType::RegLo, OperandType::Imm(8)], operands)?[..]
-                else { unreachable!() };
-
-            Ok(Instruction::Li(reg, val))
-        },
-        _ => unreachable!()
-    }
+/// Substitute the operands into one line of a pseudo-instruction expansion
+fn expand_line(line: &str, operands: &[Operand]) -> Result<(String, Vec<Operand>), String> {
+    let (name, rest) = line.split_once(' ').unwrap_or((line, ""));
+
+    let args = rest.split(',')
+        .map(str::trim)
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| match arg.strip_prefix('{').and_then(|arg| arg.strip_suffix('}')) {
+            Some("data_word") => Ok(Operand::Immediate(isa::current().addressing.data_word() as i32)),
+            Some(n) => n.parse::<usize>().ok()
+                .and_then(|n| operands.get(n).cloned())
+                .ok_or_else(|| format!("Expected an operand {{{}}}", n)),
+            None => parse_operand(arg).map(|(_, op)| op)
+                .map_err(|_| format!("Invalid operand '{}' in the expansion '{}'", arg, line)),
+        })
+        .collect::<Result<Vec<Operand>, String>>()?;
+
+    Ok((name.into(), args))
 }
 
-pub fn make_insns(op: PseudoInstruction, operands: &[Operand]) -> Result<Vec<Instruction>, InvalidOperands> {
-    eprintln!("make_insns: {:?} {:?}", op, operands);
-    
+pub fn make_insns(op: &Mnemonic, operands: &[Operand]) -> Result<Vec<Instruction>, InvalidOperands> {
     match op {
-        PseudoInstruction::Push => {
-            // Push expands to a sub/add and a load/store
-            let [reg] = do_convert_operands(op, &[OperandType::Reg], operands)?[..]
-                else { unreachable!() };
+        Mnemonic::Real(defs) => {
+            // The first encoding that accepts the operands, or the first error
+            let mut first_error = None;
+            for def in defs {
+                match convert_operands(def, operands) {
+                    Ok(insn) => return Ok(vec![insn]),
+                    Err(e) => { first_error.get_or_insert(e); },
+                }
+            }
 
-            Ok(vec![
-                Instruction::Li(7, 4), // li r7, 4
-                Instruction::Alu(Opcode::Sub, 15, 15, 7), // Sub r15, r15, r7
-                Instruction::Mem(Opcode::Sw, reg, 15, 0)
-            ])
+            Err(first_error.unwrap())
         },
-        PseudoInstruction::Pop => {
-            // Pop expands to a load/store and an add/sub
-            let [reg] = do_convert_operands(op, &[OperandType::Reg], operands)?[..]
-                else { unreachable!() };
-
-            Ok(vec![
-                Instruction::Mem(Opcode::Lw, reg, 15, 0),
-                Instruction::Li(7, 4), // li r7, 4
-                Instruction::Alu(Opcode::Add, 15, 15, 7) // Add r15, r15, r7
-            ])
+        Mnemonic::Pseudo(pseudo) => {
+            let error = |message: String| InvalidOperands(pseudo.mnemonic.clone(), message);
+
+            // One more than the highest {n} in the expansion
+            let arity = pseudo.expansion.iter()
+                .flat_map(|line| line.split('{').skip(1))
+                .filter_map(|arg| arg.split('}').next()?.parse::<usize>().ok())
+                .max()
+                .map_or(0, |n| n + 1);
+            if operands.len() != arity {
+                return Err(error(format!("Expected {} operands", arity)));
+            }
+
+            let mut insns = Vec::<Instruction>::new();
+            for line in &pseudo.expansion {
+                let (name, args) = expand_line(line, operands).map_err(error)?;
+                let op = name_to_op(&name).map_err(|e| error(e.to_string()))?;
+
+                insns.extend(make_insns(&op, &args)?);
+            }
+
+            Ok(insns)
         }
-        simple_op =>
-            make_single_insn(simple_op, operands).map(|insn| vec![insn])
     }
 }
@@ -0,0 +1,1064 @@
+use std::error::Error;
+use std::sync::OnceLock;
+
+use nom::{
+    branch::alt, bytes::complete::{tag, take_while1}, character::complete::{char, digit1, hex_digit1, none_of, one_of},
+    combinator::{map_res, opt, recognize, value}, multi::{many0, separated_list0}, sequence::{delimited, pair, preceded, separated_pair, terminated}, Parser
+};
+
+use crate::bytecode::{Condition, Instruction, InvalidInstruction, Opcode};
+use crate::parser::ws;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldKind {
+    // A register number, the width limits which registers fit
+    Reg,
+    Imm,
+}
+
+/// A bit field of an instruction word
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub name: String,
+    pub lsb: u32,
+    pub width: u32,
+    pub kind: FieldKind,
+    pub signed: bool,
+}
+
+impl Field {
+    fn new(name: &str, lsb: u32, width: u32, kind: FieldKind) -> Field {
+        Field { name: name.into(), lsb, width, kind, signed: false }
+    }
+
+    pub fn mask(&self) -> u16 {
+        (((1u32 << self.width) - 1) << self.lsb) as u16
+    }
+
+    pub fn extract(&self, word: u16) -> u16 {
+        (word & self.mask()) >> self.lsb
+    }
+
+    pub fn insert(&self, word: u16, value: u16) -> u16 {
+        (word & !self.mask()) | ((value << self.lsb) & self.mask())
+    }
+
+    pub fn fits(&self, value: u16) -> bool {
+        (value as u32) < (1 << self.width)
+    }
+
+    /// The field value read as two's complement
+    pub fn sign_extend(&self, value: u16) -> i32 {
+        let shift = 32 - self.width;
+        ((value as i32) << shift) >> shift
+    }
+
+    /// The number the field value stands for, sign extended if it's signed
+    pub fn value(&self, value: u16) -> i32 {
+        if self.signed { self.sign_extend(value) } else { value as i32 }
+    }
+
+    /// Whether the number can be stored in the field, see `value`
+    pub fn holds(&self, value: i64) -> bool {
+        if self.signed {
+            let half = 1i64 << (self.width - 1);
+            (-half..half).contains(&value)
+        } else {
+            (0..1i64 << self.width).contains(&value)
+        }
+    }
+}
+
+/// What an instruction does, independent of how it's encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Alu(Opcode),
+    Mem(Opcode),
+    AluImm(Opcode),
+    Branch(Condition),
+    Jump,
+    JumpReg,
+    JumpLink,
+    Li,
+}
+
+impl Operation {
+    /// Every operation the simulator implements
+    pub fn all() -> Vec<Operation> {
+        let alu = [Opcode::Add, Opcode::Sub, Opcode::Or, Opcode::And, Opcode::Xor, Opcode::Not, Opcode::Shl, Opcode::Shr];
+        let conds = [Condition::Equal, Condition::NotEqual, Condition::LessThan, Condition::GreaterThanEqual];
+
+        alu.into_iter().map(Operation::Alu)
+            .chain([Opcode::Lw, Opcode::Sw, Opcode::Lb, Opcode::Lbu, Opcode::Sb].map(Operation::Mem))
+            .chain(conds.into_iter().map(Operation::Branch))
+            .chain([Operation::Jump, Operation::JumpReg, Operation::JumpLink, Operation::Li])
+            .chain([Opcode::Mul, Opcode::Cmp, Opcode::Sar, Opcode::Rotr, Opcode::Rotl].map(Operation::Alu))
+            .chain([Operation::AluImm(Opcode::Addi)])
+            .collect()
+    }
+
+    /// The name in ISA descriptions
+    pub fn name(self) -> &'static str {
+        match self {
+            Operation::Alu(Opcode::Add) => "add",
+            Operation::Alu(Opcode::Sub) => "sub",
+            Operation::Alu(Opcode::Or) => "or",
+            Operation::Alu(Opcode::And) => "and",
+            Operation::Alu(Opcode::Xor) => "xor",
+            Operation::Alu(Opcode::Not) => "not",
+            Operation::Alu(Opcode::Shl) => "shl",
+            Operation::Alu(Opcode::Shr) => "shr",
+            Operation::Mem(Opcode::Lw) => "lw",
+            Operation::Mem(Opcode::Sw) => "sw",
+            Operation::Mem(Opcode::Lb) => "lb",
+            Operation::Mem(Opcode::Lbu) => "lbu",
+            Operation::Mem(Opcode::Sb) => "sb",
+            Operation::Alu(Opcode::Mul) => "mul",
+            Operation::Alu(Opcode::Cmp) => "cmp",
+            Operation::Alu(Opcode::Sar) => "sar",
+            Operation::Alu(Opcode::Rotr) => "rotr",
+            Operation::Alu(Opcode::Rotl) => "rotl",
+            Operation::AluImm(Opcode::Addi) => "addi",
+            Operation::Alu(_) | Operation::Mem(_) | Operation::AluImm(_) => "invalid",
+            Operation::Branch(Condition::Equal) => "beq",
+            Operation::Branch(Condition::NotEqual) => "bne",
+            Operation::Branch(Condition::LessThan) => "blt",
+            Operation::Branch(Condition::GreaterThanEqual) => "bge",
+            Operation::Jump => "jmp",
+            Operation::JumpReg => "jmp_reg",
+            Operation::JumpLink => "jalr",
+            Operation::Li => "li",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Operation> {
+        Operation::all().into_iter().find(|op| op.name() == name)
+    }
+
+    /// Names of the fields its instructions have
+    pub fn fields(self) -> Vec<&'static str> {
+        Instruction::from_fields(self, |_| None).field_values().into_iter().map(|(name, _)| name).collect()
+    }
+}
+
+impl Instruction {
+    pub fn operation(&self) -> Operation {
+        match *self {
+            Instruction::Alu(opcode, ..) => Operation::Alu(opcode),
+            Instruction::Mem(opcode, ..) => Operation::Mem(opcode),
+            Instruction::AluImm(opcode, ..) => Operation::AluImm(opcode),
+            Instruction::Branch(cond, _) => Operation::Branch(cond),
+            Instruction::Jump(_) => Operation::Jump,
+            Instruction::JumpReg(_) => Operation::JumpReg,
+            Instruction::JumpLink(..) => Operation::JumpLink,
+            Instruction::Li(..) => Operation::Li,
+        }
+    }
+
+    /// Operand values by field name, see `InsnDef::fields`
+    pub fn field_values(&self) -> Vec<(&'static str, u16)> {
+        match *self {
+            Instruction::Alu(_, rd, rt, rs) => vec![("rd", rd), ("rt", rt), ("rs", rs)],
+            Instruction::Mem(_, rd, rt, off) => vec![("rd", rd), ("rt", rt), ("off", off)],
+            Instruction::AluImm(_, rd, imm) => vec![("rd", rd), ("imm", imm)],
+            Instruction::Branch(_, off) => vec![("off", off)],
+            Instruction::Jump(addr) => vec![("addr", addr)],
+            Instruction::JumpReg(rd) => vec![("rd", rd)],
+            Instruction::JumpLink(rd, rt) => vec![("rd", rd), ("rt", rt)],
+            Instruction::Li(rd, imm) => vec![("rd", rd), ("imm", imm)],
+        }
+    }
+
+    /// The inverse of `field_values`, fields that aren't given are zero
+    pub fn from_fields(operation: Operation, value: impl Fn(&str) -> Option<u16>) -> Instruction {
+        let value = |name| value(name).unwrap_or(0);
+
+        match operation {
+            Operation::Alu(opcode) => Instruction::Alu(opcode, value("rd"), value("rt"), value("rs")),
+            Operation::Mem(opcode) => Instruction::Mem(opcode, value("rd"), value("rt"), value("off")),
+            Operation::AluImm(opcode) => Instruction::AluImm(opcode, value("rd"), value("imm")),
+            Operation::Branch(cond) => Instruction::Branch(cond, value("off")),
+            Operation::Jump => Instruction::Jump(value("addr")),
+            Operation::JumpReg => Instruction::JumpReg(value("rd")),
+            Operation::JumpLink => Instruction::JumpLink(value("rd"), value("rt")),
+            Operation::Li => Instruction::Li(value("rd"), value("imm")),
+        }
+    }
+}
+
+/// One encoding of an instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InsnDef {
+    pub mnemonic: String,
+    pub operation: Operation,
+    // Value of the opcode field
+    pub opcode: u16,
+    pub fields: Vec<Field>,
+    // Fields with a value fixed by the encoding, e.g. the branch condition
+    pub fixed: Vec<(String, u16)>,
+    // Names of the fields written as operands, in assembly order
+    pub operands: Vec<String>,
+}
+
+impl InsnDef {
+    pub fn field(&self, name: &str) -> Option<&Field> {
+        self.fields.iter().find(|field| field.name == name)
+    }
+
+    /// Operand fields in assembly order
+    pub fn operand_fields(&self) -> impl Iterator<Item = &Field> {
+        self.operands.iter().map(|name| self.field(name).unwrap())
+    }
+}
+
+/// An assembler mnemonic that expands to other instructions. Each line of
+/// the expansion is an instruction with `{n}` standing for operand n and
+/// `{data_word}` for `Addressing::data_word`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PseudoDef {
+    pub mnemonic: String,
+    pub expansion: Vec<String>,
+}
+
+/// What one address counts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddrUnit {
+    Byte,
+    Word,
+}
+
+impl AddrUnit {
+    pub fn from_name(name: &str) -> Option<AddrUnit> {
+        match name {
+            "byte" => Some(AddrUnit::Byte),
+            "word" => Some(AddrUnit::Word),
+            _ => None,
+        }
+    }
+}
+
+/// How programs address memory: the values of labels, jump and branch
+/// targets, `.org` and the registers used by lw, sw and jmp rN.
+///
+/// Only what the program sees changes. Objects, layouts, listings and the
+/// debugger keep counting .text in instructions and .data in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Addressing {
+    pub text: AddrUnit,
+    pub data: AddrUnit,
+    // Instruction words are zero-extended to this in binaries
+    pub insn_bytes: u32,
+    // What lw and sw move and .word in .data emits
+    pub data_word_bytes: u32,
+}
+
+impl Default for Addressing {
+    // Instructions by index and data by byte, as sasm always has
+    fn default() -> Self {
+        Addressing { text: AddrUnit::Word, data: AddrUnit::Byte, insn_bytes: 2, data_word_bytes: 4 }
+    }
+}
+
+impl Addressing {
+    /// Addresses taken up by one instruction
+    pub fn text_step(&self) -> u32 {
+        match self.text {
+            AddrUnit::Byte => self.insn_bytes,
+            AddrUnit::Word => 1,
+        }
+    }
+
+    /// Bytes in one .data address
+    pub fn data_step(&self) -> u32 {
+        match self.data {
+            AddrUnit::Byte => 1,
+            AddrUnit::Word => self.data_word_bytes,
+        }
+    }
+
+    /// The address of the instruction at an index
+    pub fn text_addr(&self, index: u32) -> u32 {
+        index.wrapping_mul(self.text_step())
+    }
+
+    /// The index of the instruction at an address, None if it's in the middle of one
+    pub fn text_index(&self, addr: u32) -> Option<u32> {
+        addr.is_multiple_of(self.text_step()).then(|| addr / self.text_step())
+    }
+
+    /// The address of a byte of .data
+    pub fn data_addr(&self, byte: u32) -> u32 {
+        byte / self.data_step()
+    }
+
+    /// The byte at a .data address
+    pub fn data_byte(&self, addr: u32) -> u32 {
+        addr.wrapping_mul(self.data_step())
+    }
+
+    /// One data word in .data addresses, what push and pop move r15 by
+    pub fn data_word(&self) -> u32 {
+        self.data_word_bytes / self.data_step()
+    }
+}
+
+/// Everything that depends on how instructions are encoded: the assembler,
+/// disassembler, linker and simulator all work from this table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Isa {
+    // Instruction words are at most 16 bits
+    pub word_bits: u32,
+    pub opcode: Field,
+    pub registers: u32,
+    // Tried in order, so the first encoding of an operation is the one used
+    pub instructions: Vec<InsnDef>,
+    pub pseudos: Vec<PseudoDef>,
+    pub addressing: Addressing,
+}
+
+impl Isa {
+    /// The encodings the assembler has always produced
+    pub fn builtin() -> Isa {
+        use FieldKind::{Imm, Reg};
+
+        let alu = || vec![Field::new("rd", 0, 4, Reg), Field::new("rt", 4, 4, Reg), Field::new("rs", 8, 3, Reg)];
+        let mem = || vec![Field::new("rd", 0, 4, Reg), Field::new("rt", 4, 4, Reg), Field::new("off", 8, 3, Imm)];
+        // Offset from the next instruction
+        let branch = || vec![Field { signed: true, ..Field::new("off", 0, 9, Imm) }, Field::new("cond", 9, 2, Imm)];
+
+        let def = |mnemonic: &str, operation, opcode, fields, operands: &[&str]| InsnDef {
+            mnemonic: mnemonic.into(),
+            operation,
+            opcode,
+            fields,
+            fixed: Vec::new(),
+            operands: operands.iter().map(|name| name.to_string()).collect(),
+        };
+        let branch_def = |mnemonic: &str, cond, bits| InsnDef {
+            fixed: vec![("cond".into(), bits)],
+            ..def(mnemonic, Operation::Branch(cond), 0b11100, branch(), &["off"])
+        };
+
+        let instructions = vec![
+            def("add", Operation::Alu(Opcode::Add), 0b00000, alu(), &["rd", "rt", "rs"]),
+            def("sub", Operation::Alu(Opcode::Sub), 0b00001, alu(), &["rd", "rt", "rs"]),
+            def("or", Operation::Alu(Opcode::Or), 0b00010, alu(), &["rd", "rt", "rs"]),
+            def("and", Operation::Alu(Opcode::And), 0b00011, alu(), &["rd", "rt", "rs"]),
+            def("xor", Operation::Alu(Opcode::Xor), 0b00100, alu(), &["rd", "rt", "rs"]),
+            // rs is unused, but decoded so every word with this opcode is valid
+            def("not", Operation::Alu(Opcode::Not), 0b00101, alu(), &["rd", "rt"]),
+            def("shl", Operation::Alu(Opcode::Shl), 0b00110, alu(), &["rd", "rt", "rs"]),
+            def("shr", Operation::Alu(Opcode::Shr), 0b00111, alu(), &["rd", "rt", "rs"]),
+            def("lw", Operation::Mem(Opcode::Lw), 0b10000, mem(), &["rd", "rt", "off"]),
+            def("sw", Operation::Mem(Opcode::Sw), 0b11000, mem(), &["rd", "rt", "off"]),
+            // The offset counts bytes
+            def("lb", Operation::Mem(Opcode::Lb), 0b10001, mem(), &["rd", "rt", "off"]),
+            def("lbu", Operation::Mem(Opcode::Lbu), 0b10010, mem(), &["rd", "rt", "off"]),
+            def("sb", Operation::Mem(Opcode::Sb), 0b11001, mem(), &["rd", "rt", "off"]),
+            branch_def("beq", Condition::Equal, 0b00),
+            branch_def("bne", Condition::NotEqual, 0b01),
+            branch_def("blt", Condition::LessThan, 0b10),
+            branch_def("bge", Condition::GreaterThanEqual, 0b11),
+            def("jmp", Operation::Jump, 0b11101, vec![Field::new("addr", 0, 11, Imm)], &["addr"]),
+            // Both take a register holding an address, with the other bits zero
+            def("jmp", Operation::JumpReg, 0b11111, vec![Field::new("rd", 0, 4, Reg)], &["rd"]),
+            def("jalr", Operation::JumpLink, 0b11110, vec![Field::new("rd", 0, 4, Reg), Field::new("rt", 4, 4, Reg)], &["rd", "rt"]),
+            def("li", Operation::Li, 0b10100, vec![Field::new("rd", 8, 3, Reg), Field::new("imm", 0, 8, Imm)], &["rd", "imm"]),
+        ];
+
+        let pseudo = |mnemonic: &str, expansion: &[&str]| PseudoDef {
+            mnemonic: mnemonic.into(),
+            expansion: expansion.iter().map(|line| line.to_string()).collect(),
+        };
+
+        let pseudos = vec![
+            pseudo("nop", &["add r0, r0, r0"]),
+            pseudo("cmp", &["sub r0, {0}, {1}"]),
+            pseudo("push", &["li r7, {data_word}", "sub r15, r15, r7", "sw {0}, r15, 0"]),
+            pseudo("pop", &["lw {0}, r15, 0", "li r7, {data_word}", "add r15, r15, r7"]),
+        ];
+
+        Isa {
+            word_bits: 16,
+            opcode: Field::new("opcode", 11, 5, Imm),
+            registers: 16,
+            instructions,
+            pseudos,
+            addressing: Addressing::default(),
+        }
+    }
+
+    /// Check the table is consistent, which a loaded description may not be
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=16).contains(&self.word_bits) {
+            return Err("word_bits must be between 1 and 16".into());
+        }
+
+        // The simulator has 16
+        if !(1..=16).contains(&self.registers) {
+            return Err("registers must be between 1 and 16".into());
+        }
+
+        let addressing = &self.addressing;
+        if !(1..=4).contains(&addressing.insn_bytes) || addressing.insn_bytes * 8 < self.word_bits {
+            return Err(format!("insn_bytes must be between 1 and 4 and hold a {}-bit word", self.word_bits));
+        }
+
+        // Registers are 32 bits
+        if ![1, 2, 4].contains(&addressing.data_word_bytes) {
+            return Err("data_word_bytes must be 1, 2 or 4".into());
+        }
+
+        let in_word = |field: &Field| field.width > 0 && field.lsb.checked_add(field.width).is_some_and(|end| end <= self.word_bits);
+        if !in_word(&self.opcode) {
+            return Err(format!("The opcode field does not fit in a {}-bit word", self.word_bits));
+        }
+
+        for def in &self.instructions {
+            let what = format!("instruction '{}'", def.mnemonic);
+
+            if !self.opcode.fits(def.opcode) {
+                return Err(format!("Opcode {:#b} of {} does not fit in the opcode field", def.opcode, what));
+            }
+
+            let mut used = self.opcode.mask();
+            for field in &def.fields {
+                if !in_word(field) {
+                    return Err(format!("Field '{}' of {} does not fit in a {}-bit word", field.name, what, self.word_bits));
+                }
+
+                if used & field.mask() != 0 {
+                    return Err(format!("Field '{}' of {} overlaps another field", field.name, what));
+                }
+                used |= field.mask();
+
+                let fixed = def.fixed.iter().any(|(name, _)| *name == field.name);
+                if !fixed && !def.operation.fields().contains(&field.name.as_str()) {
+                    return Err(format!("The {} operation of {} has no field '{}'", def.operation.name(), what, field.name));
+                }
+            }
+
+            if let Some(name) = def.operands.iter().find(|name| def.field(name).is_none()) {
+                return Err(format!("Operand '{}' of {} is not one of its fields", name, what));
+            }
+
+            for (name, value) in &def.fixed {
+                if !def.field(name).is_some_and(|field| field.fits(*value)) {
+                    return Err(format!("Fixed field '{}' of {} is not a field or its value does not fit", name, what));
+                }
+            }
+        }
+
+        // Encodings sharing an opcode are told apart by their fixed fields
+        for (i, a) in self.instructions.iter().enumerate() {
+            for b in &self.instructions[i + 1..] {
+                let distinct = a.fixed.iter().any(|(name, value)| b.fixed.iter().any(|(other, v)| other == name && v != value));
+                if a.opcode == b.opcode && !distinct {
+                    return Err(format!("'{}' and '{}' have the same encoding", a.mnemonic, b.mnemonic));
+                }
+            }
+        }
+
+        for pseudo in &self.pseudos {
+            if !self.defs_named(&pseudo.mnemonic).is_empty() {
+                return Err(format!("Pseudo-instruction '{}' has the name of an instruction", pseudo.mnemonic));
+            }
+
+            // Only real instructions, so expansions can't recurse
+            for line in &pseudo.expansion {
+                let name = line.split_whitespace().next().unwrap_or_default();
+                if self.defs_named(name).is_empty() {
+                    return Err(format!("The expansion of '{}' uses '{}', which is not an instruction", pseudo.mnemonic, name));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The built-in encodings plus the extension: multiply, a real compare
+    /// in place of the pseudo-instruction, arithmetic shift right, rotates
+    /// and add immediate
+    pub fn extended() -> Isa {
+        use FieldKind::{Imm, Reg};
+
+        let mut isa = Isa::builtin();
+        let alu = isa.def_for(Operation::Alu(Opcode::Add)).unwrap().fields.clone();
+
+        let def = |mnemonic: &str, operation, opcode, fields, operands: &[&str]| InsnDef {
+            mnemonic: mnemonic.into(),
+            operation,
+            opcode,
+            fields,
+            fixed: Vec::new(),
+            operands: operands.iter().map(|name| name.to_string()).collect(),
+        };
+
+        isa.instructions.extend([
+            def("mul", Operation::Alu(Opcode::Mul), 0b01000, alu.clone(), &["rd", "rt", "rs"]),
+            // Only sets the flags, so it has no rd
+            def("cmp", Operation::Alu(Opcode::Cmp), 0b01001, alu[1..].to_vec(), &["rt", "rs"]),
+            def("sar", Operation::Alu(Opcode::Sar), 0b01010, alu.clone(), &["rd", "rt", "rs"]),
+            def("rotr", Operation::Alu(Opcode::Rotr), 0b01011, alu.clone(), &["rd", "rt", "rs"]),
+            def("rotl", Operation::Alu(Opcode::Rotl), 0b01100, alu.clone(), &["rd", "rt", "rs"]),
+            def("addi", Operation::AluImm(Opcode::Addi), 0b01101,
+                vec![Field::new("rd", 0, 4, Reg), Field { signed: true, ..Field::new("imm", 4, 7, Imm) }], &["rd", "imm"]),
+        ]);
+        isa.pseudos.retain(|pseudo| pseudo.mnemonic != "cmp");
+
+        isa
+    }
+
+    pub fn def_for(&self, operation: Operation) -> Option<&InsnDef> {
+        self.instructions.iter().find(|def| def.operation == operation)
+    }
+
+    /// Encodings with the mnemonic, in the order to try them
+    pub fn defs_named(&self, mnemonic: &str) -> Vec<&InsnDef> {
+        self.instructions.iter().filter(|def| def.mnemonic == mnemonic).collect()
+    }
+
+    pub fn pseudo(&self, mnemonic: &str) -> Option<&PseudoDef> {
+        self.pseudos.iter().find(|pseudo| pseudo.mnemonic == mnemonic)
+    }
+
+    pub fn encode(&self, insn: Instruction) -> Result<u16, InvalidInstruction> {
+        let def = self.def_for(insn.operation())
+            .ok_or_else(|| InvalidInstruction(format!("{:?} has no encoding", insn.operation())))?;
+
+        let mut word = self.opcode.insert(0, def.opcode);
+        for (name, value) in insn.field_values().into_iter().chain(def.fixed.iter().map(|(name, value)| (name.as_str(), *value))) {
+            let Some(field) = def.field(name) else {
+                continue;
+            };
+
+            if !field.fits(value) {
+                return Err(InvalidInstruction(format!("{} does not fit in the {}-bit {} field of {}", value, field.width, name, def.mnemonic)));
+            }
+
+            word = field.insert(word, value);
+        }
+
+        Ok(word)
+    }
+
+    /// The encoding of a word. Bits outside every field must be zero.
+    pub fn def_of(&self, word: u16) -> Result<&InsnDef, InvalidInstruction> {
+        let opcode = self.opcode.extract(word);
+
+        self.instructions.iter()
+            .filter(|def| def.opcode == opcode)
+            .find(|def| {
+                let covered = def.fields.iter().fold(self.opcode.mask(), |mask, field| mask | field.mask());
+                let fixed = def.fixed.iter().all(|(name, value)| def.field(name).is_some_and(|field| field.extract(word) == *value));
+
+                word & !covered == 0 && fixed
+            })
+            .ok_or_else(|| InvalidInstruction(format!("Unknown instruction: {:#06x}", word)))
+    }
+
+    pub fn decode(&self, word: u16) -> Result<Instruction, InvalidInstruction> {
+        let def = self.def_of(word)?;
+        Ok(Instruction::from_fields(def.operation, |name| def.field(name).map(|field| field.extract(word))))
+    }
+
+    /// Mnemonic and operands, in the syntax the assembler accepts
+    pub fn format(&self, insn: Instruction) -> Result<String, InvalidInstruction> {
+        let def = self.def_for(insn.operation())
+            .ok_or_else(|| InvalidInstruction(format!("{:?} has no encoding", insn.operation())))?;
+        let values = insn.field_values();
+
+        let operands = def.operand_fields().map(|field| {
+            let value = values.iter().find(|(name, _)| *name == field.name).map_or(0, |(_, value)| *value);
+
+            match field.kind {
+                FieldKind::Reg => format!("r{}", value),
+                FieldKind::Imm => field.value(value).to_string(),
+            }
+        }).collect::<Vec<String>>();
+
+        if operands.is_empty() {
+            return Ok(def.mnemonic.clone());
+        }
+
+        Ok(format!("{} {}", def.mnemonic, operands.join(", ")))
+    }
+}
+
+/// A value in the TOML subset ISA descriptions are written in
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Int(i64),
+    Bool(bool),
+    Str(String),
+    Array(Vec<Value>),
+    // Keys in the order they were written
+    Table(Vec<(String, Value)>),
+}
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "an integer",
+            Value::Bool(_) => "a boolean",
+            Value::Str(_) => "a string",
+            Value::Array(_) => "an array",
+            Value::Table(_) => "a table",
+        }
+    }
+}
+
+fn parse_key(input: &str) -> nom::IResult<&str, String> {
+    alt((
+        take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-').map(String::from),
+        parse_string,
+    )).parse(input)
+}
+
+fn parse_string(input: &str) -> nom::IResult<&str, String> {
+    let escape = preceded(char('\\'), alt((
+        value('\\', char('\\')),
+        value('"', char('"')),
+        value('\n', char('n')),
+        value('\t', char('t')),
+    )));
+
+    delimited(char('"'), many0(alt((escape, none_of("\"\\\n")))), char('"'))
+        .map(|chars| chars.into_iter().collect())
+        .parse(input)
+}
+
+fn parse_int(input: &str) -> nom::IResult<&str, i64> {
+    let digits = |radix: u32| take_while1(move |c: char| c.is_digit(radix) || c == '_');
+    let from_radix = |radix: u32| move |s: &str| i64::from_str_radix(&s.replace('_', ""), radix);
+
+    alt((
+        map_res(preceded(tag("0x"), hex_digit1), |s| i64::from_str_radix(s, 16)),
+        map_res(preceded(tag("0b"), digits(2)), from_radix(2)),
+        map_res(recognize(pair(opt(one_of("+-")), digit1)), |s: &str| s.parse::<i64>()),
+    )).parse(input)
+}
+
+fn parse_value(input: &str) -> nom::IResult<&str, Value> {
+    alt((
+        parse_string.map(Value::Str),
+        value(Value::Bool(true), tag("true")),
+        value(Value::Bool(false), tag("false")),
+        parse_int.map(Value::Int),
+        delimited(
+            ws(char('[')),
+            terminated(separated_list0(ws(char(',')), ws(parse_value)), opt(ws(char(',')))),
+            char(']'),
+        ).map(Value::Array),
+        delimited(
+            ws(char('{')),
+            separated_list0(ws(char(',')), parse_entry),
+            char('}'),
+        ).map(Value::Table),
+    )).parse(input)
+}
+
+fn parse_entry(input: &str) -> nom::IResult<&str, (String, Value)> {
+    separated_pair(ws(parse_key), char('='), ws(parse_value)).parse(input)
+}
+
+/// Where the entries after a header go
+enum Header {
+    // [formats.alu]
+    Table(Vec<String>),
+    // [[instruction]]
+    ArrayItem(String),
+}
+
+fn parse_header(input: &str) -> nom::IResult<&str, Header> {
+    ws(alt((
+        delimited(tag("[["), ws(parse_key), tag("]]")).map(Header::ArrayItem),
+        delimited(char('['), separated_list0(char('.'), ws(parse_key)), char(']')).map(Header::Table),
+    ))).parse(input)
+}
+
+/// The table at `path` under `root`, created if it doesn't exist
+fn table_at<'a>(root: &'a mut Vec<(String, Value)>, path: &[String]) -> Result<&'a mut Vec<(String, Value)>, String> {
+    let Some((first, rest)) = path.split_first() else {
+        return Ok(root);
+    };
+
+    if !root.iter().any(|(key, _)| key == first) {
+        root.push((first.clone(), Value::Table(Vec::new())));
+    }
+
+    match root.iter_mut().find(|(key, _)| key == first) {
+        Some((_, Value::Table(table))) => table_at(table, rest),
+        _ => Err(format!("'{}' is not a table", first)),
+    }
+}
+
+/// Parse a TOML document, as far as ISA descriptions need: key/value
+/// pairs, `[table]` and `[[array]]` headers, integers, booleans, strings,
+/// arrays and inline tables.
+fn parse_document(input: &str) -> Result<Vec<(String, Value)>, Box<dyn Error>> {
+    let (rest, (entries, sections)) = pair(
+        many0(parse_entry),
+        many0(pair(parse_header, many0(parse_entry))),
+    ).parse(input)
+        .map_err(|e| e.to_owned())?;
+
+    if !rest.is_empty() {
+        let line = input[..input.len() - rest.len()].lines().count().max(1);
+        return Err(format!("Invalid ISA description near line {}", line).into());
+    }
+
+    let mut root = entries;
+    for (header, entries) in sections {
+        match header {
+            Header::Table(path) => table_at(&mut root, &path)?.extend(entries),
+            Header::ArrayItem(name) => {
+                if !root.iter().any(|(key, _)| *key == name) {
+                    root.push((name.clone(), Value::Array(Vec::new())));
+                }
+
+                match root.iter_mut().find(|(key, _)| *key == name) {
+                    Some((_, Value::Array(items))) => items.push(Value::Table(entries)),
+                    _ => return Err(format!("'{}' is not an array of tables", name).into()),
+                }
+            }
+        }
+    }
+
+    Ok(root)
+}
+
+/// Typed access to the keys of a table, for error messages that say where
+/// in the description a value is wrong
+struct Entries<'a> {
+    what: String,
+    entries: &'a [(String, Value)],
+}
+
+impl<'a> Entries<'a> {
+    fn new(what: String, value: &'a Value) -> Result<Entries<'a>, String> {
+        match value {
+            Value::Table(entries) => Ok(Entries { what, entries }),
+            other => Err(format!("{} must be a table, not {}", what, other.type_name())),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&'a Value> {
+        self.entries.iter().find(|(name, _)| name == key).map(|(_, value)| value)
+    }
+
+    fn required(&self, key: &str) -> Result<&'a Value, String> {
+        self.get(key).ok_or_else(|| format!("{} has no '{}'", self.what, key))
+    }
+
+    fn wrong_type(&self, key: &str, expected: &str, value: &Value) -> String {
+        format!("'{}' of {} must be {}, not {}", key, self.what, expected, value.type_name())
+    }
+
+    fn int(&self, key: &str) -> Result<u32, String> {
+        match self.required(key)? {
+            Value::Int(n) => u32::try_from(*n).map_err(|_| format!("'{}' of {} is out of range", key, self.what)),
+            other => Err(self.wrong_type(key, "an integer", other)),
+        }
+    }
+
+    fn u16(&self, key: &str) -> Result<u16, String> {
+        u16::try_from(self.int(key)?).map_err(|_| format!("'{}' of {} is out of range", key, self.what))
+    }
+
+    fn string(&self, key: &str) -> Result<&'a str, String> {
+        match self.required(key)? {
+            Value::Str(s) => Ok(s),
+            other => Err(self.wrong_type(key, "a string", other)),
+        }
+    }
+
+    fn strings(&self, key: &str) -> Result<Vec<String>, String> {
+        let Some(value) = self.get(key) else {
+            return Ok(Vec::new());
+        };
+
+        let Value::Array(items) = value else {
+            return Err(self.wrong_type(key, "an array of strings", value));
+        };
+
+        items.iter().map(|item| match item {
+            Value::Str(s) => Ok(s.clone()),
+            other => Err(self.wrong_type(key, "an array of strings", other)),
+        }).collect()
+    }
+
+    fn flag(&self, key: &str) -> Result<bool, String> {
+        match self.get(key) {
+            None => Ok(false),
+            Some(Value::Bool(b)) => Ok(*b),
+            Some(other) => Err(self.wrong_type(key, "a boolean", other)),
+        }
+    }
+
+    fn tables(&self, key: &str) -> Result<Vec<Entries<'a>>, String> {
+        match self.get(key) {
+            None => Ok(Vec::new()),
+            Some(Value::Array(items)) => items.iter().enumerate()
+                .map(|(i, item)| Entries::new(format!("{} {}", key, i + 1), item))
+                .collect(),
+            Some(other) => Err(self.wrong_type(key, "an array of tables", other)),
+        }
+    }
+}
+
+fn field_from(name: &str, entries: &Entries) -> Result<Field, String> {
+    let kind = match entries.get("kind") {
+        None => FieldKind::Imm,
+        Some(Value::Str(kind)) if kind == "imm" => FieldKind::Imm,
+        Some(Value::Str(kind)) if kind == "reg" => FieldKind::Reg,
+        Some(_) => return Err(format!("'kind' of {} must be \"reg\" or \"imm\"", entries.what)),
+    };
+
+    Ok(Field {
+        name: name.into(),
+        lsb: entries.int("lsb")?,
+        width: entries.int("width")?,
+        kind,
+        signed: entries.flag("signed")?,
+    })
+}
+
+/// The `[addressing]` table, where every key is optional
+fn addressing_from(entries: &Entries) -> Result<Addressing, String> {
+    let default = Addressing::default();
+
+    let unit = |key: &str, default: AddrUnit| match entries.get(key) {
+        None => Ok(default),
+        Some(_) => AddrUnit::from_name(entries.string(key)?)
+            .ok_or_else(|| format!("'{}' of {} must be \"byte\" or \"word\"", key, entries.what)),
+    };
+    let int = |key: &str, default: u32| match entries.get(key) {
+        None => Ok(default),
+        Some(_) => entries.int(key),
+    };
+
+    Ok(Addressing {
+        text: unit("text", default.text)?,
+        data: unit("data", default.data)?,
+        insn_bytes: int("insn_bytes", default.insn_bytes)?,
+        data_word_bytes: int("data_word_bytes", default.data_word_bytes)?,
+    })
+}
+
+/// Parse an ISA description. `isa/sasm.toml` is the built-in one, written
+/// out as an example:
+///
+/// ```text
+/// word_bits = 16
+/// registers = 16
+/// opcode = { lsb = 11, width = 5 }
+///
+/// [formats.li]
+/// rd = { lsb = 8, width = 3, kind = "reg" }
+/// imm = { lsb = 0, width = 8 }
+///
+/// [[instruction]]
+/// mnemonic = "li"
+/// format = "li"
+/// opcode = 0b10100
+/// operands = ["rd", "imm"]
+///
+/// [[pseudo]]
+/// mnemonic = "nop"
+/// expansion = ["add r0, r0, r0"]
+/// ```
+///
+/// An instruction's `operation` is what the simulator does for it, by
+/// default the operation named like the mnemonic. `fixed` gives fields
+/// whose value is part of the encoding, such as a branch condition. An
+/// optional `[addressing]` table sets the `Addressing` model.
+pub fn parse_isa(input: &str) -> Result<Isa, Box<dyn Error>> {
+    let root = Value::Table(parse_document(input)?);
+    let root = Entries::new("the ISA description".into(), &root)?;
+
+    let opcode = field_from("opcode", &Entries::new("opcode".into(), root.required("opcode")?)?)?;
+
+    let mut formats = Vec::<(String, Vec<Field>)>::new();
+    if let Some(value) = root.get("formats") {
+        let Value::Table(entries) = value else {
+            return Err("'formats' must be a table of formats".into());
+        };
+
+        for (name, format) in entries {
+            let format = Entries::new(format!("format '{}'", name), format)?;
+            let fields = format.entries.iter()
+                .map(|(field, value)| field_from(field, &Entries::new(format!("field '{}' of format '{}'", field, name), value)?))
+                .collect::<Result<Vec<Field>, String>>()?;
+
+            formats.push((name.clone(), fields));
+        }
+    }
+
+    let mut instructions = Vec::<InsnDef>::new();
+    for insn in root.tables("instruction")? {
+        let mnemonic = insn.string("mnemonic")?;
+        let insn = Entries { what: format!("instruction '{}'", mnemonic), ..insn };
+
+        let operation = match insn.get("operation") {
+            Some(Value::Str(name)) => name.as_str(),
+            Some(other) => return Err(insn.wrong_type("operation", "a string", other).into()),
+            None => mnemonic,
+        };
+        let operation = Operation::from_name(operation)
+            .ok_or_else(|| format!("Unknown operation '{}' for {}", operation, insn.what))?;
+
+        let format = insn.string("format")?;
+        let fields = formats.iter()
+            .find(|(name, _)| name == format)
+            .map(|(_, fields)| fields.clone())
+            .ok_or_else(|| format!("{} uses unknown format '{}'", insn.what, format))?;
+
+        let fixed = match insn.get("fixed") {
+            None => Vec::new(),
+            Some(value) => {
+                let fixed = Entries::new(format!("'fixed' of {}", insn.what), value)?;
+                fixed.entries.iter()
+                    .map(|(name, _)| Ok((name.clone(), fixed.u16(name)?)))
+                    .collect::<Result<Vec<(String, u16)>, String>>()?
+            }
+        };
+
+        instructions.push(InsnDef {
+            mnemonic: mnemonic.into(),
+            operation,
+            opcode: insn.u16("opcode")?,
+            fields,
+            fixed,
+            operands: insn.strings("operands")?,
+        });
+    }
+
+    let pseudos = root.tables("pseudo")?.iter()
+        .map(|pseudo| Ok(PseudoDef { mnemonic: pseudo.string("mnemonic")?.into(), expansion: pseudo.strings("expansion")? }))
+        .collect::<Result<Vec<PseudoDef>, String>>()?;
+
+    let addressing = match root.get("addressing") {
+        None => Addressing::default(),
+        Some(value) => addressing_from(&Entries::new("addressing".into(), value)?)?,
+    };
+
+    let isa = Isa {
+        word_bits: root.int("word_bits")?,
+        opcode,
+        registers: root.int("registers")?,
+        instructions,
+        pseudos,
+        addressing,
+    };
+    isa.validate()?;
+
+    Ok(isa)
+}
+
+static CURRENT: OnceLock<Isa> = OnceLock::new();
+
+/// The instruction set in use, the built-in one unless `set` was called
+pub fn current() -> &'static Isa {
+    CURRENT.get_or_init(Isa::builtin)
+}
+
+/// Use another instruction set. This must happen before anything uses the
+/// current one, so that one program isn't a mix of two.
+pub fn set(isa: Isa) -> Result<(), Box<dyn Error>> {
+    CURRENT.set(isa).map_err(|_| "The instruction set is already in use".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::{Condition, Instruction, Opcode};
+    use crate::isa::{parse_isa, AddrUnit, Addressing, Isa, Operation};
+
+    #[test]
+    fn test_table_lookups() {
+        let isa = Isa::builtin();
+
+        let jumps = isa.defs_named("jmp").iter().map(|def| def.operation).collect::<Vec<Operation>>();
+        assert_eq!(jumps, [Operation::Jump, Operation::JumpReg]);
+
+        // The condition is a fixed field, so it picks the encoding
+        let bge = isa.encode(Instruction::Branch(Condition::GreaterThanEqual, 5)).unwrap();
+        assert_eq!(isa.def_of(bge).unwrap().mnemonic, "bge");
+        assert_eq!(isa.format(isa.decode(bge).unwrap()).unwrap(), "bge 5");
+
+        // Reserved bits of jmp rN
+        assert!(isa.decode(0xf813).is_err());
+        assert!(isa.encode(Instruction::Li(8, 0)).is_err());
+    }
+
+    #[test]
+    fn test_shipped_description_is_builtin() {
+        let isa = parse_isa(include_str!("../isa/sasm.toml")).unwrap();
+        assert_eq!(isa, Isa::builtin());
+        assert!(Isa::builtin().validate().is_ok());
+    }
+
+    #[test]
+    fn test_variant_description() {
+        let variant = "
+            word_bits = 12
+            registers = 8
+            opcode = { lsb = 9, width = 3 }   # fewer, shorter opcodes
+
+            [formats.alu]
+            rd = { lsb = 0, width = 3, kind = \"reg\" }
+            rt = { lsb = 3, width = 3, kind = \"reg\" }
+            rs = { lsb = 6, width = 3, kind = \"reg\" }
+
+            [[instruction]]
+            mnemonic = \"plus\"
+            operation = \"add\"
+            format = \"alu\"
+            opcode = 0b011
+            operands = [\"rd\", \"rt\", \"rs\",]
+
+            [[pseudo]]
+            mnemonic = \"double\"
+            expansion = [\"plus {0}, {0}, {0}\"]
+        ";
+        let isa = parse_isa(variant).unwrap();
+
+        let insn = Instruction::Alu(Opcode::Add, 1, 2, 3);
+        assert_eq!(isa.encode(insn).unwrap(), 0b011_011_010_001);
+        assert_eq!(isa.format(insn).unwrap(), "plus r1, r2, r3");
+        assert_eq!(isa.pseudo("double").unwrap().expansion, ["plus {0}, {0}, {0}"]);
+
+        // A field past the 12-bit word
+        let wide = variant.replace("lsb = 9, width = 3 }", "lsb = 10, width = 3 }");
+        assert!(parse_isa(&wide).unwrap_err().to_string().contains("opcode field"));
+
+        let unknown = variant.replace("\"add\"", "\"div\"");
+        assert!(parse_isa(&unknown).unwrap_err().to_string().contains("Unknown operation 'div'"));
+
+        // Opcodes are 16 bits, not wrapped into range
+        let huge = variant.replace("opcode = 0b011", "opcode = 65568");
+        assert!(parse_isa(&huge).unwrap_err().to_string().contains("'opcode' of instruction 'plus' is out of range"));
+
+        assert!(parse_isa("word_bits = ").is_err());
+    }
+
+    #[test]
+    fn test_addressing() {
+        let description = include_str!("../isa/sasm.toml").replace("text = \"word\"\ndata = \"byte\"", "text = \"byte\"\ndata = \"word\"");
+        let isa = parse_isa(&description).unwrap();
+        let addressing = isa.addressing;
+        assert_eq!((addressing.text, addressing.data), (AddrUnit::Byte, AddrUnit::Word));
+
+        // Two bytes per instruction and four per data word
+        assert_eq!(addressing.text_addr(3), 6);
+        assert_eq!(addressing.text_index(6), Some(3));
+        assert_eq!(addressing.text_index(7), None);
+        assert_eq!((addressing.data_addr(8), addressing.data_byte(2), addressing.data_word()), (2, 8, 1));
+
+        let default = Addressing::default();
+        assert_eq!((default.text_addr(3), default.data_addr(8), default.data_word()), (3, 8, 4));
+
+        let odd = Isa { addressing: Addressing { data_word_bytes: 3, ..default }, ..Isa::builtin() };
+        assert!(odd.validate().unwrap_err().contains("data_word_bytes"));
+    }
+}
@@ -0,0 +1,157 @@
+use nom::{
+    branch::alt, bytes::complete::tag, character::complete::{alphanumeric1, char}, combinator::{map_opt, opt}, multi::many0, sequence::{delimited, pair, preceded}, Parser
+};
+use std::error::Error;
+
+use crate::parser::{parse_name, parse_u32, ws, Segment};
+
+/// A named block of memory, e.g. the instruction ROM.
+///
+/// Regions holding `.text` are measured in instruction words and regions
+/// holding `.data` in bytes, matching how the linker addresses each section.
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub name: String,
+    pub origin: u32,
+    pub length: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct MemoryLayout {
+    pub regions: Vec<Region>,
+    pub sections: Vec<(Segment, String)>,
+}
+
+impl Default for MemoryLayout {
+    fn default() -> Self {
+        // Jump targets are 11 bits and the bus starts at 0x10000
+        MemoryLayout {
+            regions: vec![
+                Region { name: "rom".into(), origin: 0, length: 0x800 },
+                Region { name: "ram".into(), origin: 0, length: 0x10000 },
+                Region { name: "bus".into(), origin: 0x10000, length: 0x20 },
+            ],
+            sections: vec![
+                (Segment::Text, "rom".into()),
+                (Segment::Data, "ram".into()),
+            ],
+        }
+    }
+}
+
+impl MemoryLayout {
+    pub fn region(&self, name: &str) -> Option<&Region> {
+        self.regions.iter().find(|region| region.name == name)
+    }
+
+    pub fn region_for(&self, section: Segment) -> Option<&Region> {
+        self.sections.iter()
+            .find(|(seg, _)| *seg == section)
+            .and_then(|(_, name)| self.region(name))
+    }
+}
+
+pub fn section_name(section: Segment) -> &'static str {
+    match section {
+        Segment::Text => ".text",
+        Segment::Data => ".data",
+    }
+}
+
+fn parse_number(input: &str) -> nom::IResult<&str, u32> {
+    map_opt(alphanumeric1, parse_u32).parse(input)
+}
+
+fn parse_region(input: &str) -> nom::IResult<&str, Region> {
+    // rom : ORIGIN = 0x0, LENGTH = 0x800
+    let (input, name) = ws(parse_name).parse(input)?;
+    let (input, _) = ws(char(':')).parse(input)?;
+    let (input, origin) = preceded(pair(ws(tag("ORIGIN")), ws(char('='))), parse_number).parse(input)?;
+    let (input, _) = ws(char(',')).parse(input)?;
+    let (input, length) = preceded(pair(ws(tag("LENGTH")), ws(char('='))), parse_number).parse(input)?;
+
+    Ok((input, Region { name: name.into(), origin, length }))
+}
+
+fn parse_placement(input: &str) -> nom::IResult<&str, (Segment, String)> {
+    // .text > rom
+    let (input, section) = ws(alt((
+        tag(".text").map(|_| Segment::Text),
+        tag(".data").map(|_| Segment::Data),
+    ))).parse(input)?;
+    let (input, region) = preceded(ws(char('>')), ws(parse_name)).parse(input)?;
+
+    Ok((input, (section, region.into())))
+}
+
+fn parse_block<'a, O, P>(name: &'static str, item: P) -> impl Parser<&'a str, Output = Vec<O>, Error = nom::error::Error<&'a str>>
+where
+    P: Parser<&'a str, Output = O, Error = nom::error::Error<&'a str>>,
+{
+    preceded(ws(tag(name)), delimited(ws(char('{')), many0(item), ws(char('}'))))
+}
+
+/// Parse a memory layout in a small subset of the GNU ld script syntax:
+///
+/// ```text
+/// MEMORY {
+///     rom : ORIGIN = 0x0, LENGTH = 0x800
+///     ram : ORIGIN = 0x0, LENGTH = 0x10000
+/// }
+/// SECTIONS {
+///     .text > rom
+///     .data > ram
+/// }
+/// ```
+pub fn parse_layout(input: &str) -> Result<MemoryLayout, Box<dyn Error>> {
+    let (rest, (regions, sections)) = pair(
+        parse_block("MEMORY", parse_region),
+        opt(parse_block("SECTIONS", parse_placement)),
+    ).parse(input)
+        .map_err(|e| e.to_owned())?;
+
+    if !rest.is_empty() {
+        let line = input[..input.len() - rest.len()].lines().count().max(1);
+        return Err(format!("Invalid memory layout near line {}", line).into());
+    }
+
+    let layout = MemoryLayout { regions, sections: sections.unwrap_or_default() };
+
+    for (section, region) in &layout.sections {
+        if layout.region(region).is_none() {
+            return Err(format!("{} is placed in unknown region '{}'", section_name(*section), region).into());
+        }
+
+        if layout.sections.iter().filter(|(_, r)| r == region).count() > 1 {
+            return Err(format!("Region '{}' holds more than one section", region).into());
+        }
+    }
+
+    Ok(layout)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::layout::parse_layout;
+    use crate::parser::Segment;
+
+    #[test]
+    fn test_parse_layout() {
+        let input = "
+            # instruction ROM and data RAM
+            MEMORY {
+                rom : ORIGIN = 0x10, LENGTH = 256
+                ram : ORIGIN = 0x0, LENGTH = 0x100
+            }
+            SECTIONS {
+                .text > rom
+                .data > ram
+            }
+        ";
+
+        let layout = parse_layout(input).unwrap();
+        let rom = layout.region_for(Segment::Text).unwrap();
+        assert_eq!((rom.name.as_str(), rom.origin, rom.length), ("rom", 0x10, 256));
+        assert_eq!(layout.region_for(Segment::Data).unwrap().name, "ram");
+    }
+}
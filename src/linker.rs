@@ -0,0 +1,408 @@
+use std::collections::{HashMap, HashSet};
+use std::error;
+
+use crate::archive::Archive;
+use crate::disasm::disassemble;
+use crate::isa;
+use crate::layout::{section_name, MemoryLayout};
+use crate::object::{ObjectFile, RelocKind, Relocation, Symbol};
+use crate::parser::Segment;
+
+#[derive(Debug, Clone)]
+pub struct LinkError(pub String);
+
+impl std::fmt::Display for LinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Link error: {}.", self.0)
+    }
+}
+
+impl error::Error for LinkError {}
+
+/// A fully linked program, ready to be written out or executed.
+#[derive(Debug, Clone, Default)]
+pub struct Image {
+    pub text: Vec<u16>,
+    // Source line of each word in .text, 0 if unknown
+    pub lines: Vec<u32>,
+    pub data: Vec<u8>,
+    pub text_base: u32,
+    pub data_base: u32,
+    pub symbols: HashMap<String, (Segment, u32)>,
+    // Every label including locals, for debugging
+    pub labels: Vec<(String, Segment, u32)>,
+}
+
+impl Image {
+    /// Raw big-endian instruction words, the format the assembler has always
+    /// produced, each zero-extended to the instruction size.
+    pub fn to_binary(&self) -> Vec<u8> {
+        let size = isa::current().addressing.insn_bytes as usize;
+        self.text.iter().flat_map(|insn| (*insn as u32).to_be_bytes()[4 - size..].to_vec()).collect()
+    }
+
+    pub fn label(&self, name: &str) -> Option<(Segment, u32)> {
+        self.labels.iter()
+            .find(|(label, _, _)| label == name)
+            .map(|(_, section, addr)| (*section, *addr))
+    }
+
+    /// Source line of the instruction at a .text address
+    pub fn line_at(&self, addr: u32) -> Option<u32> {
+        let idx = addr.checked_sub(self.text_base)?;
+        self.lines.get(idx as usize).copied().filter(|line| *line != 0)
+    }
+
+    /// Size of a section in its own address unit (words for .text, bytes for .data)
+    pub fn section_len(&self, section: Segment) -> u32 {
+        match section {
+            Segment::Text => self.text.len() as u32,
+            Segment::Data => self.data.len() as u32,
+        }
+    }
+}
+
+/// Per-region usage summary, in the style of `ld --print-memory-usage`.
+pub fn memory_usage(layout: &MemoryLayout, image: &Image) -> String {
+    let mut report = format!("{:<10} {:>10} {:>10} {:>10} {:>7}\n", "Region", "Origin", "Used", "Size", "%age");
+
+    for region in &layout.regions {
+        let used = layout.sections.iter()
+            .filter(|(_, name)| *name == region.name)
+            .map(|(section, _)| image.section_len(*section))
+            .sum::<u32>();
+        let percent = if region.length == 0 { 0.0 } else { 100.0 * used as f64 / region.length as f64 };
+
+        report += &format!("{:<10} {:>#10x} {:>10} {:>10} {:>6.2}%\n", region.name, region.origin, used, region.length, percent);
+    }
+
+    report
+}
+
+/// Address, encoding and disassembly of every word with the labels and the
+/// source line it came from, followed by the initial contents of .data.
+pub fn listing(image: &Image, source: &str) -> String {
+    let source = source.lines().collect::<Vec<&str>>();
+    let labels_at = |section: Segment, addr: u32| image.labels.iter()
+        .filter(move |(_, sec, a)| *sec == section && *a == addr)
+        .map(|(name, _, _)| format!("{}:\n", name))
+        .collect::<String>();
+
+    let mut out = String::from(".text\n");
+    let mut last_line = 0;
+
+    for (i, item) in disassemble(&image.text, image.text_base).iter().enumerate() {
+        out += &labels_at(Segment::Text, item.addr);
+
+        let line = image.lines.get(i).copied().unwrap_or(0);
+
+        if line != 0 && line != last_line {
+            let code = source.get(line as usize - 1).map_or("", |code| code.trim());
+            out += &format!("{:04x}  {:04x}  {:<24}  {:>4}: {}\n", item.addr, item.word, item.text, line, code);
+        } else {
+            out += &format!("{:04x}  {:04x}  {}\n", item.addr, item.word, item.text);
+        }
+
+        last_line = line;
+    }
+    out += &labels_at(Segment::Text, image.text_base + image.text.len() as u32);
+
+    if !image.data.is_empty() {
+        out += ".data\n";
+
+        let size = isa::current().addressing.data_word_bytes;
+        for (i, word) in image.data.chunks(size as usize).enumerate() {
+            let addr = image.data_base + size * i as u32;
+            out += &labels_at(Segment::Data, addr);
+
+            let hex = word.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+            out += &format!("{:04x}  {}\n", addr, hex);
+        }
+        out += &labels_at(Segment::Data, image.data_base + image.data.len() as u32);
+    }
+
+    out
+}
+
+/// Symbols referenced by relocations that no object defines
+fn undefined_symbols(objects: &[ObjectFile]) -> Vec<String> {
+    let defined = objects.iter()
+        .flat_map(|object| object.defined_globals())
+        .map(|sym| sym.name.as_str())
+        .collect::<HashSet<&str>>();
+
+    let mut undefined = Vec::<String>::new();
+    for object in objects {
+        for reloc in &object.relocations {
+            let local = object.symbol(&reloc.symbol).is_some_and(|sym| sym.section.is_some());
+
+            if !local && !defined.contains(reloc.symbol.as_str()) && !undefined.contains(&reloc.symbol) {
+                undefined.push(reloc.symbol.clone());
+            }
+        }
+    }
+
+    undefined
+}
+
+/// Append the archive members that define symbols the objects leave
+/// undefined, repeating until members stop introducing new references.
+pub fn pull_members(mut objects: Vec<ObjectFile>, archives: &[Archive]) -> Vec<ObjectFile> {
+    let mut pulled = HashSet::<(usize, usize)>::new();
+
+    loop {
+        let next = undefined_symbols(&objects).iter().find_map(|sym| {
+            archives.iter().enumerate().find_map(|(a, archive)| {
+                archive.find_definition(sym)
+                    .map(|m| (a, m))
+                    .filter(|member| !pulled.contains(member))
+            })
+        });
+
+        let Some((a, m)) = next else {
+            return objects;
+        };
+
+        pulled.insert((a, m));
+        objects.push(archives[a].members[m].1.clone());
+    }
+}
+
+struct Placement {
+    text_base: u32,
+    data_base: u32,
+}
+
+impl Placement {
+    fn address(&self, sym: &Symbol) -> Option<(Segment, u32)> {
+        match sym.section? {
+            Segment::Text => Some((Segment::Text, self.text_base + sym.value)),
+            Segment::Data => Some((Segment::Data, self.data_base + sym.value)),
+        }
+    }
+}
+
+/// Lay out the objects one after the other in the regions given by the
+/// layout, resolve symbols and apply relocations.
+pub fn link(objects: &[ObjectFile], layout: &MemoryLayout) -> Result<Image, LinkError> {
+    let mut image = Image {
+        text_base: layout.region_for(Segment::Text).map_or(0, |region| region.origin),
+        data_base: layout.region_for(Segment::Data).map_or(0, |region| region.origin),
+        ..Default::default()
+    };
+    let mut placements = Vec::<Placement>::new();
+
+    for object in objects {
+        placements.push(Placement {
+            text_base: image.text_base + image.text.len() as u32,
+            data_base: image.data_base + image.data.len() as u32,
+        });
+
+        image.text.extend_from_slice(&object.text);
+        image.data.extend_from_slice(&object.data);
+
+        if object.lines.len() == object.text.len() {
+            image.lines.extend_from_slice(&object.lines);
+        } else {
+            image.lines.resize(image.text.len(), 0);
+        }
+    }
+
+    for section in [Segment::Text, Segment::Data] {
+        let len = image.section_len(section);
+        if len == 0 {
+            continue;
+        }
+
+        let Some(region) = layout.region_for(section) else {
+            return Err(LinkError(format!("no region for {}", section_name(section))));
+        };
+
+        if len > region.length {
+            return Err(LinkError(format!(
+                "{} ({}) overflows region '{}' ({}) by {}",
+                section_name(section), len, region.name, region.length, len - region.length
+            )));
+        }
+    }
+
+    for (object, placement) in objects.iter().zip(&placements) {
+        for sym in &object.symbols {
+            if let Some((section, addr)) = placement.address(sym) {
+                image.labels.push((sym.name.clone(), section, addr));
+            }
+        }
+
+        for sym in object.defined_globals() {
+            let addr = placement.address(sym).unwrap();
+
+            if image.symbols.insert(sym.name.clone(), addr).is_some() {
+                return Err(LinkError(format!("duplicate symbol '{}'", sym.name)));
+            }
+        }
+    }
+
+    for (object, placement) in objects.iter().zip(&placements) {
+        for reloc in &object.relocations {
+            // Symbols local to the object take priority over globals
+            let target = object.symbol(&reloc.symbol)
+                .and_then(|sym| placement.address(sym))
+                .or_else(|| image.symbols.get(&reloc.symbol).copied())
+                .ok_or_else(|| LinkError(format!("undefined symbol '{}'", reloc.symbol)))?;
+
+            apply_relocation(&mut image, placement, reloc, target)?;
+        }
+    }
+
+    Ok(image)
+}
+
+fn apply_relocation(image: &mut Image, placement: &Placement, reloc: &Relocation, target: (Segment, u32)) -> Result<(), LinkError> {
+    let (target_section, target_addr) = target;
+
+    // The address the program sees
+    let addressing = isa::current().addressing;
+    let value = match target_section {
+        Segment::Text => addressing.text_addr(target_addr),
+        Segment::Data => addressing.data_addr(target_addr),
+    };
+
+    let field = |bits: u32, value: i64| -> Result<u16, LinkError> {
+        if value < 0 || value >= (1 << bits) {
+            return Err(LinkError(format!(
+                "'{}' ({}) does not fit in the {}-bit field of a {:?} relocation",
+                reloc.symbol, value, bits, reloc.kind
+            )));
+        }
+
+        Ok(value as u16)
+    };
+
+    match reloc.section {
+        Segment::Text => {
+            let addr = placement.text_base + reloc.offset;
+            let word = &mut image.text[(addr - image.text_base) as usize];
+
+            let name = match reloc.kind {
+                RelocKind::Jump => {
+                    if target_section != Segment::Text {
+                        return Err(LinkError(format!("cannot jump to data symbol '{}'", reloc.symbol)));
+                    }
+
+                    "addr"
+                },
+                RelocKind::Imm8 => "imm",
+                RelocKind::Branch => {
+                    if target_section != Segment::Text {
+                        return Err(LinkError(format!("cannot branch to data symbol '{}'", reloc.symbol)));
+                    }
+
+                    "off"
+                },
+                RelocKind::Word32 =>
+                    return Err(LinkError("32-bit relocation in .text".into()))
+            };
+
+            // The field to patch comes from the encoding of the placeholder word
+            let def = isa::current().def_of(*word).map_err(|e| LinkError(e.to_string()))?;
+            let patched = def.field(name).ok_or_else(|| LinkError(format!(
+                "{:?} relocation on '{}', which has no {} field", reloc.kind, def.mnemonic, name
+            )))?;
+
+            let value = match reloc.kind {
+                // Same encoding the assembler uses for local branches
+                RelocKind::Branch => {
+                    let off = (target_addr as i64 - (addr as i64 + 1)) * addressing.text_step() as i64;
+                    if !patched.holds(off) {
+                        return Err(LinkError(format!(
+                            "branch to '{}' is out of range, {} does not fit in the {}-bit offset",
+                            reloc.symbol, off, patched.width
+                        )));
+                    }
+
+                    off & ((1 << patched.width) - 1)
+                },
+                _ => value as i64,
+            };
+
+            *word = patched.insert(*word, field(patched.width, value)?);
+        },
+        Segment::Data => {
+            if reloc.kind != RelocKind::Word32 {
+                return Err(LinkError(format!("{:?} relocation in .data", reloc.kind)));
+            }
+
+            let size = addressing.data_word_bytes as usize;
+            let addr = (placement.data_base - image.data_base + reloc.offset) as usize;
+            image.data[addr..addr + size].copy_from_slice(&value.to_be_bytes()[4 - size..]);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::Archive;
+    use crate::assembler::assemble;
+    use crate::layout::{parse_layout, MemoryLayout};
+    use crate::linker::{link, pull_members};
+
+    // A jump to a label defined in another object gets patched with its final address
+    #[test]
+    fn test_link_extern_jump() {
+        let main = assemble(".extern func\nmain:\n    jmp func\n").unwrap();
+        let lib = assemble(".global func\n    nop\nfunc:\n    nop\n").unwrap();
+
+        let image = link(&[main, lib], &MemoryLayout::default()).unwrap();
+
+        // func is the second instruction of the second object
+        assert_eq!(image.text[0] & 0x7ff, 2);
+        assert_eq!(image.symbols.get("func").unwrap().1, 2);
+    }
+
+    // Sections are placed at their region's origin and may not outgrow it
+    #[test]
+    fn test_link_layout() {
+        let layout = parse_layout("MEMORY { rom : ORIGIN = 0x10, LENGTH = 2 } SECTIONS { .text > rom }").unwrap();
+
+        let image = link(&[assemble("start:\n    jmp start\n").unwrap()], &layout).unwrap();
+        assert_eq!(image.text[0] & 0x7ff, 0x10);
+
+        let big = assemble("    nop\n    nop\n    nop\n").unwrap();
+        assert!(link(&[big], &layout).is_err());
+    }
+
+    // Offsets are signed and count from the next instruction, locally and across objects
+    #[test]
+    fn test_branch_offsets() {
+        let local = link(&[assemble("    beq fwd\n    nop\nfwd:\n    bne fwd\n").unwrap()], &MemoryLayout::default()).unwrap();
+        assert_eq!(local.text, [0xe001, 0x0000, 0xe3ff]);
+
+        let main = assemble(".extern func\n    beq func\n").unwrap();
+        let lib = assemble(".global func\n    nop\nfunc:\n    nop\n").unwrap();
+        assert_eq!(link(&[main, lib], &MemoryLayout::default()).unwrap().text[0], 0xe001);
+
+        let nops = "    nop\n".repeat(255);
+        assert!(assemble(&format!("    beq far\n{}far:\n", nops)).is_ok());
+        assert!(assemble(&format!("    beq far\n{}    nop\nfar:\n", nops)).is_err());
+        assert!(assemble(&format!("back:\n{}    beq back\n", nops)).is_ok());
+        assert!(assemble(&format!("back:\n{}    nop\n    beq back\n", nops)).is_err());
+    }
+
+    // Only the members that resolve an undefined symbol are linked, including
+    // ones needed by other pulled members
+    #[test]
+    fn test_pull_archive_members() {
+        let mut archive = Archive::default();
+        archive.insert("mul.o".into(), assemble(".global mul\nmul:\n    nop\n").unwrap());
+        archive.insert("div.o".into(), assemble(".global div\n.extern sub\ndiv:\n    jmp sub\n").unwrap());
+        archive.insert("sub.o".into(), assemble(".global sub\nsub:\n    nop\n").unwrap());
+
+        let main = assemble(".extern div\n    jmp div\n").unwrap();
+        let objects = pull_members(vec![main], &[archive]);
+
+        assert_eq!(objects.len(), 3);
+        assert!(objects.iter().all(|object| object.symbol("mul").is_none()));
+    }
+}
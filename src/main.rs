@@ -1,146 +1,539 @@
 mod parser;
 mod bytecode;
+mod isa;
 mod instruction;
+mod object;
+mod archive;
+mod assembler;
+mod layout;
+mod linker;
+mod simulator;
+mod devices;
+mod debugger;
+mod gdbstub;
+mod trace;
+mod disasm;
+mod cfg;
+mod rtl;
+mod timing;
+mod asmtest;
 
 use std::collections::HashMap;
 use std::error::Error;
+use std::fs;
 use std::io::{self, Read, Write};
 
-use crate::bytecode::{disassemble, encode_instruction, Condition, Instruction};
-use crate::instruction::{make_insns, name_to_op, PseudoInstruction};
-use crate::parser::{parse_operand, AsmObject, Operand};
+use crate::archive::Archive;
+use crate::layout::MemoryLayout;
+use crate::linker::Image;
+use crate::object::ObjectFile;
+use crate::parser::parse_u32;
+use crate::simulator::{Machine, StepObserver};
 
-struct IncompleteInstruction(PseudoInstruction, Vec<Operand>);
+fn option_value<'a>(args: &'a [String], name: &str) -> Option<&'a String> {
+    args.iter().position(|arg| arg == name).and_then(|i| args.get(i + 1))
+}
 
-fn assemble(input: &str) -> Result<(), Box<dyn Error>> {
-    let objects = parser::parse_asm(input)?;
+fn number_option(args: &[String], name: &str, default: u64) -> Result<u64, Box<dyn Error>> {
+    let Some(value) = option_value(args, name) else {
+        return Ok(default);
+    };
 
-    let mut constants = HashMap::<String, String>::new();
-    
-    // Do two passes incase a constant relies on another constant
-    for _i in 1..2 {
-        for obj in &objects {
-            if let AsmObject::Constant(key, value) = obj {
-                let mut value = value;
-                while constants.contains_key(value) {
-                    value = constants.get(value).unwrap();
-                }
+    parse_u32(value).map(u64::from).ok_or_else(|| format!("Invalid number for {}: '{}'", name, value).into())
+}
 
-                constants.insert(key.into(), value.into());
-            }
+fn load_layout(path: Option<&String>) -> Result<MemoryLayout, Box<dyn Error>> {
+    match path {
+        Some(path) => {
+            let input = fs::read_to_string(path)
+                .map_err(|e| format!("{}: {}", path, e))?;
+            layout::parse_layout(&input)
+                .map_err(|e| format!("{}: {}", path, e).into())
+        },
+        None => Ok(MemoryLayout::default())
+    }
+}
+
+fn link_objects(objects: &[ObjectFile], args: &[String]) -> Result<Image, Box<dyn Error>> {
+    let layout = load_layout(option_value(args, "--layout"))?;
+    let image = linker::link(objects, &layout)?;
+
+    if args.iter().any(|arg| arg == "--print-memory-usage") {
+        eprint!("{}", linker::memory_usage(&layout, &image));
+    }
+
+    Ok(image)
+}
+
+fn write_output(path: Option<&String>, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+    match path {
+        Some(path) => fs::write(path, bytes)?,
+        None => io::stdout().write_all(bytes)?,
+    }
+
+    Ok(())
+}
+
+fn link_cmd(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut output = None;
+    let mut objects = Vec::<ObjectFile>::new();
+    let mut archives = Vec::<Archive>::new();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-o" => {
+                output = Some(iter.next().ok_or("Expected a file name after -o")?);
+                continue;
+            },
+            "--layout" => {
+                iter.next();
+                continue;
+            },
+            "--print-memory-usage" => continue,
+            _ => ()
+        }
+
+        let bytes = fs::read(arg)
+            .map_err(|e| format!("{}: {}", arg, e))?;
+
+        if Archive::is_archive(&bytes) {
+            archives.push(Archive::from_bytes(&bytes)
+                .map_err(|e| format!("{}: {}", arg, e))?);
+        } else {
+            objects.push(ObjectFile::from_bytes(&bytes)
+                .map_err(|e| format!("{}: {}", arg, e))?);
+        }
+    }
+
+    if objects.is_empty() {
+        return Err("usage: sasm link [-o output] [--layout file] [--print-memory-usage] <object|archive>...".into());
+    }
+
+    let objects = linker::pull_members(objects, &archives);
+    let image = link_objects(&objects, args)?;
+    write_output(output, &image.to_binary())
+}
+
+/// Load a program to execute: `.asm` files are assembled and linked,
+/// anything else is taken to be a raw binary.
+fn load_program(path: &str, args: &[String]) -> Result<Image, Box<dyn Error>> {
+    if path.ends_with(".asm") {
+        let input = fs::read_to_string(path)
+            .map_err(|e| format!("{}: {}", path, e))?;
+        let object = assembler::assemble(&input)?;
+
+        return link_objects(&[object], args);
+    }
+
+    let bytes = fs::read(path)
+        .map_err(|e| format!("{}: {}", path, e))?;
+    let text = disasm::words_from_bytes(&bytes)
+        .map_err(|e| format!("{}: {}", path, e))?;
+
+    Ok(Image {
+        text,
+        ..Default::default()
+    })
+}
+
+/// A machine with the board peripherals attached and the I/O script loaded
+fn make_machine(image: &Image, args: &[String]) -> Result<Machine, Box<dyn Error>> {
+    let memory_size = number_option(args, "--mem-size", simulator::DEFAULT_MEMORY_SIZE as u64)?;
+
+    let mut machine = Machine::new(image, memory_size as u32)?;
+    machine.devices = devices::board_devices();
+
+    if let Some(path) = option_value(args, "--io") {
+        let input = fs::read_to_string(path)
+            .map_err(|e| format!("{}: {}", path, e))?;
+        machine.io_events = devices::parse_io_script(&input)
+            .map_err(|e| format!("{}: {}", path, e))?
+            .into();
+    }
+
+    Ok(machine)
+}
+
+fn timing_config(args: &[String]) -> Result<timing::TimingConfig, Box<dyn Error>> {
+    let default = timing::TimingConfig::default();
+
+    Ok(timing::TimingConfig {
+        stages: number_option(args, "--stages", default.stages as u64)?.max(1) as u32,
+        branch_penalty: number_option(args, "--branch-penalty", default.branch_penalty as u64)? as u32,
+        load_use_stall: number_option(args, "--load-use-stall", default.load_use_stall as u64)? as u32,
+        mem_latency: number_option(args, "--mem-latency", default.mem_latency as u64)? as u32,
+    })
+}
+
+fn run_cmd(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let path = args.first()
+        .filter(|arg| !arg.starts_with('-'))
+        .ok_or("usage: sasm run <program> [--max-steps n] [--mem-size bytes] [--io script] [--io-stdin] [--trace file] [--vcd file] [--timing]")?;
+
+    let image = load_program(path, args)?;
+    let max_steps = number_option(args, "--max-steps", 1_000_000)?;
+    let mut machine = make_machine(&image, args)?;
+
+    // Inputs can also be typed as '<device> <value>' while running
+    if args.iter().any(|arg| arg == "--io-stdin") {
+        machine.io_input = Some(devices::spawn_terminal_input());
+    }
+
+    let mut observers = Vec::<Box<dyn StepObserver>>::new();
+
+    if let Some(path) = option_value(args, "--trace") {
+        let file = io::BufWriter::new(fs::File::create(path)?);
+        observers.push(Box::new(trace::TraceLog::new(file)));
+    }
+
+    // Each instruction is 10ns in the waveform
+    if let Some(path) = option_value(args, "--vcd") {
+        let file = io::BufWriter::new(fs::File::create(path)?);
+        observers.push(Box::new(trace::VcdWriter::new(file, &machine, 10)?));
+    }
+
+    let mut timing = args.iter().any(|arg| arg == "--timing")
+        .then(|| timing_config(args))
+        .transpose()?
+        .map(timing::TimingModel::new);
+
+    let mut observers = observers.iter_mut()
+        .map(|observer| observer.as_mut() as &mut dyn StepObserver)
+        .chain(timing.as_mut().map(|timing| timing as &mut dyn StepObserver))
+        .collect::<Vec<&mut dyn StepObserver>>();
+    let reason = machine.run(max_steps, &mut observers)?;
+
+    println!("stopped: {}", reason);
+    print!("{}", machine.dump_registers());
+
+    if let Some(timing) = timing {
+        let source = if path.ends_with(".asm") { fs::read_to_string(path)? } else { String::new() };
+        print!("{}", timing.report(&image, &source));
+    }
+
+    Ok(())
+}
+
+fn debug_cmd(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let path = args.first()
+        .filter(|arg| !arg.starts_with('-'))
+        .ok_or("usage: sasm debug <program> [--max-steps n] [--mem-size bytes] [--io script]")?;
+
+    let image = load_program(path, args)?;
+    let max_steps = number_option(args, "--max-steps", 1_000_000)?;
+    let machine = make_machine(&image, args)?;
+
+    // Source lines are only known when debugging an .asm file
+    let source = if path.ends_with(".asm") { fs::read_to_string(path)? } else { String::new() };
+
+    debugger::Debugger::new(machine, image, &source, max_steps).repl()
+}
+
+fn gdb_cmd(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let path = args.first()
+        .filter(|arg| !arg.starts_with('-'))
+        .ok_or("usage: sasm gdb <program> [--port n] [--mem-size bytes] [--io script]")?;
+
+    let image = load_program(path, args)?;
+    let machine = make_machine(&image, args)?;
+    let port = u16::try_from(number_option(args, "--port", 1234)?)
+        .map_err(|_| "--port must be at most 65535")?;
+
+    // Only listen locally, the stub has no authentication
+    let listener = std::net::TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("Waiting for gdb, use 'target remote {}'", listener.local_addr()?);
+
+    gdbstub::GdbStub::new(machine).serve(listener)
+}
+
+fn test_cmd(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let max_steps = number_option(args, "--max-steps", 1_000_000)?;
+
+    let mut paths = Vec::<String>::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--max-steps" {
+            iter.next();
+            continue;
         }
+
+        paths.push(arg.clone());
+    }
+
+    if paths.is_empty() {
+        paths.push("tests".into());
     }
 
-    let mut labels = HashMap::<String, u32>::new();
-    let mut instructions = Vec::<IncompleteInstruction>::new();
-    let mut pc = 0;
-    for obj in objects {
-        match obj {
-            AsmObject::Instruction(name, operands) => {
-                let op = name_to_op(name.as_str())?;
-                pc += op.length();
-
-                instructions.push(
-                    IncompleteInstruction(op, operands.iter().map(
-                        |op| {
-                            if let Operand::Name(name) = op {
-                                if let Some(value) = constants.get(name) {
-                                    return parse_operand(value).unwrap().1;
-                                }
-                            }
-
-                            op.to_owned()
-                        }
-                    ).collect())
-                )
+    // Directories run every .asm file directly inside them
+    let mut files = Vec::<std::path::PathBuf>::new();
+    for path in &paths {
+        let path = std::path::Path::new(path);
+
+        if path.is_dir() {
+            let mut entries = fs::read_dir(path)
+                .map_err(|e| format!("{}: {}", path.display(), e))?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()?;
+            entries.retain(|entry| entry.extension().is_some_and(|ext| ext == "asm"));
+            entries.sort();
+            files.extend(entries);
+        } else {
+            files.push(path.into());
+        }
+    }
+
+    let mut failed = 0;
+    for file in &files {
+        let result = fs::read_to_string(file)
+            .map_err(|e| e.into())
+            .and_then(|source| asmtest::run_test(&source, max_steps));
+
+        match result {
+            Ok(result) if result.failures.is_empty() =>
+                println!("PASS {} ({} steps, {} cycles)", file.display(), result.steps, result.cycles),
+            Ok(result) => {
+                println!("FAIL {}", file.display());
+                for failure in result.failures {
+                    println!("    {}", failure);
+                }
+                failed += 1;
             },
-            AsmObject::Label(name) => {
-                labels.insert( name, pc);
+            Err(e) => {
+                println!("FAIL {}", file.display());
+                println!("    {}", e);
+                failed += 1;
             },
-            _ =>
-                ()
         }
     }
 
-    let mut bytecode = Vec::<u16>::new();
+    println!("{} passed, {} failed", files.len() - failed, failed);
+
+    if failed > 0 {
+        return Err(format!("{} of {} tests failed", failed, files.len()).into());
+    }
+
+    Ok(())
+}
 
-    for ( idx, instr) in instructions.iter().enumerate() {
-        let mut operands: Vec<Operand> = Vec::<Operand>::new();
+/// The symbol map given with --symbols, see disasm::parse_symbol_map
+fn load_symbols(args: &[String]) -> Result<HashMap<u32, String>, Box<dyn Error>> {
+    let Some(path) = option_value(args, "--symbols") else {
+        return Ok(HashMap::new());
+    };
 
-        for op in
-            instr.1.iter().map(|op| match op {
-                Operand::Name(name) => {
-                    let label_addr = labels.get(name);
+    let input = fs::read_to_string(path)
+        .map_err(|e| format!("{}: {}", path, e))?;
+    disasm::parse_symbol_map(&input)
+        .map_err(|e| format!("{}: {}", path, e).into())
+}
 
-                    if label_addr.is_none() {
-                        return Err(format!("Invalid label: {}", name));
-                    }
+fn cfg_cmd(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let path = args.first()
+        .filter(|arg| !arg.starts_with('-'))
+        .ok_or("usage: sasm cfg <program> [--calls] [--json] [--symbols file] [-o output]")?;
 
-                    let label_addr = label_addr.unwrap();
+    let image = load_program(path, args)?;
 
-                    // Get the offset from this insn to the label,
-                    // include the one instruction offset
-                    let off = (idx as i32) - (*label_addr as i32) + 1;
+    // Source labels first, the symbol map wins where both name an address
+    let mut labels = HashMap::<u32, String>::new();
+    for (name, section, addr) in &image.labels {
+        if *section == parser::Segment::Text {
+            labels.entry(*addr).or_insert_with(|| name.clone());
+        }
+    }
+    labels.extend(load_symbols(args)?);
 
-                    let mut value = (*label_addr) as i32;
-                    // If the op is PseudoInstruction::Branch, we need the offset
-                    if instr.0.is_branch() {
-                        value = off as i32;
-                    }
+    let cfg = cfg::build(&image.text, image.text_base, &labels);
+    let json = args.iter().any(|arg| arg == "--json");
 
-                    Ok(Operand::Immediate(value))
-                },
-                op => Ok(op.clone())
-            }) {
+    // --calls gives the call graph between functions instead of the blocks
+    let output = if args.iter().any(|arg| arg == "--calls") {
+        let graph = cfg.call_graph();
+        if json { graph.to_json() } else { graph.to_dot() }
+    } else if json {
+        cfg.to_json()
+    } else {
+        cfg.to_dot()
+    };
 
-            if op.is_err() {
-                return Err(op.unwrap_err().into());
-            }
+    write_output(option_value(args, "-o"), output.as_bytes())
+}
 
-            operands.push(op.unwrap());
+fn gen_rtl_cmd(args: &[String]) -> Result<(), Box<dyn Error>> {
+    // --prefix names the package and the decoder module
+    let prefix = option_value(args, "--prefix").map_or("sasm", String::as_str);
+    let output = rtl::generate(isa::current(), prefix);
+
+    write_output(option_value(args, "-o"), output.as_bytes())
+}
+
+fn ar_cmd(args: &[String]) -> Result<(), Box<dyn Error>> {
+    const USAGE: &str = "usage: sasm ar <archive> <object>... | sasm ar -t <archive>";
+
+    // -t lists the members and the symbols they define
+    if args.first().is_some_and(|arg| arg == "-t") {
+        let path = args.get(1).ok_or(USAGE)?;
+        let archive = Archive::from_bytes(&fs::read(path)?)
+            .map_err(|e| format!("{}: {}", path, e))?;
+
+        for (name, object) in &archive.members {
+            println!("{}", name);
+            for sym in object.defined_globals() {
+                println!("    {}", sym.name);
+            }
         }
 
-        let mut real_insns = 
-            make_insns(instr.0, operands.as_slice())?
-            .iter()
-            .map(|insn| encode_instruction(*insn))
-            .collect::<Vec<u16>>();
+        return Ok(());
+    }
+
+    let [path, members @ ..] = args else {
+        return Err(USAGE.into());
+    };
 
-        bytecode.append(&mut real_insns);
+    if members.is_empty() {
+        return Err(USAGE.into());
     }
 
-    for insn in bytecode {
-        io::stdout().write_all(&[
-            (insn >> 8) as u8, (insn & 0xff) as u8
-        ])?;
+    // Add to an existing archive, replacing members with the same name
+    let mut archive = match fs::read(path) {
+        Ok(bytes) => Archive::from_bytes(&bytes)
+            .map_err(|e| format!("{}: {}", path, e))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Archive::default(),
+        Err(e) => return Err(format!("{}: {}", path, e).into()),
+    };
+
+    for member in members {
+        let bytes = fs::read(member)
+            .map_err(|e| format!("{}: {}", member, e))?;
+        let object = ObjectFile::from_bytes(&bytes)
+            .map_err(|e| format!("{}: {}", member, e))?;
+
+        let name = std::path::Path::new(member)
+            .file_name()
+            .map_or(member.clone(), |name| name.to_string_lossy().into_owned());
+
+        archive.insert(name, object);
     }
 
+    fs::write(path, archive.to_bytes())?;
     Ok(())
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    // check for disasm cmd line flag
-    if std::env::args().any(|arg| arg == "--disasm") {
-        let bytes = io::stdin().bytes()
-            .map(|b| b.unwrap())
-            .collect::<Vec<u8>>();
-
-        if bytes.len() % 2 != 0 {
-            eprintln!("Error: Bytecode length is not a multiple of 2");
-            return Err("Bytecode length is not a multiple of 2".into());
+/// Take `--name value` out of the arguments
+fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let Some(i) = args.iter().position(|arg| arg == name) else {
+        return Ok(None);
+    };
+
+    let value = args.get(i + 1).ok_or_else(|| format!("{} needs a value", name))?.clone();
+    args.drain(i..i + 2);
+
+    Ok(Some(value))
+}
+
+/// --isa, or SASM_ISA in the environment, loads the description of a CPU
+/// variant, see isa::parse_isa. --ext enables the extension instructions
+/// of the built-in ISA instead. --addr-unit, --insn-size and
+/// --data-word-size override its addressing model. They apply to every
+/// command, so they're taken out of the arguments here.
+fn load_isa(args: &mut Vec<String>) -> Result<(), Box<dyn Error>> {
+    let mut description = if let Some(i) = args.iter().position(|arg| arg == "--ext") {
+        args.remove(i);
+
+        if args.iter().any(|arg| arg == "--isa") {
+            return Err("--ext can't be used with --isa, describe the extension in the ISA file instead".into());
+        }
+        if std::env::var("SASM_ISA").is_ok_and(|path| !path.is_empty()) {
+            return Err("--ext can't be used with SASM_ISA, describe the extension in the ISA file instead".into());
         }
 
-        let mut pc = 0;
-        for chunk in bytes.chunks(2) {
-            let insn = u16::from_be_bytes([chunk[0], chunk[1]]);
-            disassemble(insn);
+        isa::Isa::extended()
+    } else {
+        let path = match take_option(args, "--isa")? {
+            Some(path) => Some(path),
+            None => std::env::var("SASM_ISA").ok().filter(|path| !path.is_empty()),
+        };
+
+        match path {
+            Some(path) => {
+                let input = fs::read_to_string(&path)
+                    .map_err(|e| format!("{}: {}", path, e))?;
+                isa::parse_isa(&input)
+                    .map_err(|e| format!("{}: {}", path, e))?
+            },
+            None => isa::Isa::builtin(),
+        }
+    };
+
+    let addressing = &mut description.addressing;
+    if let Some(unit) = take_option(args, "--addr-unit")? {
+        let unit = isa::AddrUnit::from_name(&unit)
+            .ok_or_else(|| format!("--addr-unit must be byte or word, not '{}'", unit))?;
+        addressing.text = unit;
+        addressing.data = unit;
+    }
+    for (name, bytes) in [("--insn-size", &mut addressing.insn_bytes), ("--data-word-size", &mut addressing.data_word_bytes)] {
+        if let Some(value) = take_option(args, name)? {
+            *bytes = value.parse().map_err(|_| format!("Invalid number for {}: '{}'", name, value))?;
         }
+    }
+    description.validate()?;
+
+    isa::set(description)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = std::env::args().collect::<Vec<String>>();
+    load_isa(&mut args)?;
+
+    match args.get(1).map(String::as_str) {
+        Some("link") => return link_cmd(&args[2..]),
+        Some("ar") => return ar_cmd(&args[2..]),
+        Some("run") => return run_cmd(&args[2..]),
+        Some("debug") => return debug_cmd(&args[2..]),
+        Some("gdb") => return gdb_cmd(&args[2..]),
+        Some("test") => return test_cmd(&args[2..]),
+        Some("cfg") => return cfg_cmd(&args[2..]),
+        Some("gen-rtl") => return gen_rtl_cmd(&args[2..]),
+        _ => ()
+    }
+
+    // check for disasm cmd line flag
+    if args.iter().any(|arg| arg == "--disasm") {
+        let mut bytes = Vec::<u8>::new();
+        io::stdin().read_to_end(&mut bytes)?;
+
+        let words = disasm::words_from_bytes(&bytes).inspect_err(|e| eprintln!("Error: {}", e))?;
+
+        // --symbols names branch and jump targets
+        let symbols = load_symbols(&args)?;
+
+        // --idioms shows cmp, push and pop instead of the instructions they expand to
+        let idioms = args.iter().any(|arg| arg == "--idioms");
+        print!("{}", disasm::disassemble_program(&words, 0, &symbols, idioms));
 
         return Ok(())
     }
 
     let input = io::read_to_string(io::stdin())?;
-    assemble(&input)?;
+    let object = assembler::assemble(&input)?;
 
-    Ok(())
+    // -c stops before linking and writes a relocatable object
+    if args.iter().any(|arg| arg == "-c") {
+        return write_output(None, &object.to_bytes());
+    }
+
+    let image = link_objects(&[object], &args)?;
+
+    // --listing prints the linked program next to its source instead
+    if args.iter().any(|arg| arg == "--listing") {
+        return write_output(None, linker::listing(&image, &input).as_bytes());
+    }
+
+    if !image.data.is_empty() {
+        eprintln!("Warning: .data ({} bytes) is not included in the raw binary", image.data.len());
+    }
+
+    write_output(None, &image.to_binary())
 }
@@ -0,0 +1,224 @@
+use std::error;
+
+use crate::parser::Segment;
+
+const MAGIC: &[u8; 4] = b"SOBJ";
+const VERSION: u8 = 2;
+
+#[derive(Debug, Clone)]
+pub struct ObjectError(pub String);
+
+impl std::fmt::Display for ObjectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid object file: {}.", self.0)
+    }
+}
+
+impl error::Error for ObjectError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocKind {
+    // Absolute address in the low 11 bits of a jmp
+    Jump,
+    // Absolute address in the low 8 bits of a li
+    Imm8,
+    // Offset to the target in the low 9 bits of a branch
+    Branch,
+    // Absolute address stored as a data word, 32 bits unless the
+    // addressing model says otherwise
+    Word32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    // None if the symbol is undefined (.extern)
+    pub section: Option<Segment>,
+    pub value: u32,
+    pub global: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Relocation {
+    pub section: Segment,
+    // Word index into .text or byte offset into .data
+    pub offset: u32,
+    pub kind: RelocKind,
+    pub symbol: String,
+}
+
+/// A relocatable object produced by the assembler and consumed by the linker.
+///
+/// `.text` is addressed in 16-bit instruction words and `.data` in bytes,
+/// both starting at zero until the linker places them.
+#[derive(Debug, Clone, Default)]
+pub struct ObjectFile {
+    pub text: Vec<u16>,
+    // Source line of each word in .text, empty if unknown
+    pub lines: Vec<u32>,
+    pub data: Vec<u8>,
+    pub symbols: Vec<Symbol>,
+    pub relocations: Vec<Relocation>,
+}
+
+impl ObjectFile {
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|sym| sym.name == name)
+    }
+
+    pub fn defined_globals(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter().filter(|sym| sym.global && sym.section.is_some())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::<u8>::new();
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+
+        out.extend_from_slice(&(self.text.len() as u32).to_be_bytes());
+        for word in &self.text {
+            out.extend_from_slice(&word.to_be_bytes());
+        }
+
+        out.extend_from_slice(&(self.lines.len() as u32).to_be_bytes());
+        for line in &self.lines {
+            out.extend_from_slice(&line.to_be_bytes());
+        }
+
+        out.extend_from_slice(&(self.data.len() as u32).to_be_bytes());
+        out.extend_from_slice(&self.data);
+
+        out.extend_from_slice(&(self.symbols.len() as u32).to_be_bytes());
+        for sym in &self.symbols {
+            write_name(&mut out, &sym.name);
+            out.push(section_to_byte(sym.section));
+            out.extend_from_slice(&sym.value.to_be_bytes());
+            out.push(sym.global as u8);
+        }
+
+        out.extend_from_slice(&(self.relocations.len() as u32).to_be_bytes());
+        for reloc in &self.relocations {
+            out.push(section_to_byte(Some(reloc.section)));
+            out.extend_from_slice(&reloc.offset.to_be_bytes());
+            out.push(reloc.kind as u8);
+            write_name(&mut out, &reloc.symbol);
+        }
+
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<ObjectFile, ObjectError> {
+        let mut reader = Reader { bytes, pos: 0 };
+
+        if reader.take(4)? != MAGIC {
+            return Err(ObjectError("bad magic".into()));
+        }
+
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(ObjectError(format!("unsupported version {}", version)));
+        }
+
+        let mut object = ObjectFile::default();
+
+        for _ in 0..reader.u32()? {
+            object.text.push(reader.u16()?);
+        }
+
+        for _ in 0..reader.u32()? {
+            object.lines.push(reader.u32()?);
+        }
+
+        let data_len = reader.u32()? as usize;
+        object.data = reader.take(data_len)?.to_vec();
+
+        for _ in 0..reader.u32()? {
+            let name = reader.name()?;
+            let section = byte_to_section(reader.u8()?)?;
+            let value = reader.u32()?;
+            let global = reader.u8()? != 0;
+
+            object.symbols.push(Symbol { name, section, value, global });
+        }
+
+        for _ in 0..reader.u32()? {
+            let section = byte_to_section(reader.u8()?)?
+                .ok_or_else(|| ObjectError("relocation in undefined section".into()))?;
+            let offset = reader.u32()?;
+            let kind = match reader.u8()? {
+                0 => RelocKind::Jump,
+                1 => RelocKind::Imm8,
+                2 => RelocKind::Branch,
+                3 => RelocKind::Word32,
+                kind => return Err(ObjectError(format!("unknown relocation kind {}", kind)))
+            };
+            let symbol = reader.name()?;
+
+            object.relocations.push(Relocation { section, offset, kind, symbol });
+        }
+
+        if reader.pos != bytes.len() {
+            return Err(ObjectError("trailing bytes".into()));
+        }
+
+        Ok(object)
+    }
+}
+
+pub fn write_name(out: &mut Vec<u8>, name: &str) {
+    out.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    out.extend_from_slice(name.as_bytes());
+}
+
+fn section_to_byte(section: Option<Segment>) -> u8 {
+    match section {
+        None => 0,
+        Some(Segment::Text) => 1,
+        Some(Segment::Data) => 2,
+    }
+}
+
+fn byte_to_section(byte: u8) -> Result<Option<Segment>, ObjectError> {
+    match byte {
+        0 => Ok(None),
+        1 => Ok(Some(Segment::Text)),
+        2 => Ok(Some(Segment::Data)),
+        _ => Err(ObjectError(format!("unknown section {}", byte)))
+    }
+}
+
+/// Big-endian cursor shared by the object and archive formats.
+pub struct Reader<'a> {
+    pub bytes: &'a [u8],
+    pub pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], ObjectError> {
+        let slice = self.bytes.get(self.pos..self.pos + len)
+            .ok_or_else(|| ObjectError("unexpected end of file".into()))?;
+        self.pos += len;
+
+        Ok(slice)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, ObjectError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, ObjectError> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, ObjectError> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn name(&mut self) -> Result<String, ObjectError> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| ObjectError("symbol name is not valid UTF-8".into()))
+    }
+}
@@ -1,10 +1,10 @@
 use nom::{
-    branch::alt, bytes::complete::tag, character::complete::{alpha1, alphanumeric0, alphanumeric1, char, space0, space1}, combinator::{not, opt, recognize}, error::ParseError, multi::{many0, many0_count, separated_list0, separated_list1}, sequence::{delimited, pair}, Parser
+    branch::alt, bytes::complete::tag, character::complete::{alpha1, alphanumeric1, char, multispace1, not_line_ending, space0, space1}, combinator::{opt, recognize}, multi::{many0, separated_list0}, sequence::{delimited, pair, preceded}, Parser
 };
 use std::error::Error;
 
-#[derive(Debug)]
-enum Segment {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Segment {
     Text,
     Data
 }
@@ -51,7 +51,7 @@ fn parse_line_ending_comment(input: &str) -> nom::IResult<&str, &str> {
     Ok((input, ""))
 }
 
-fn parse_name<'a>(input: &'a str) -> nom::IResult<&'a str, &str> {
+pub fn parse_name(input: &str) -> nom::IResult<&str, &str> {
     // Label names must start with _a-zA-Z and can contain _a-zA-Z0-9
     recognize(pair(
         alt((alpha1, tag("_"))),
@@ -59,22 +59,22 @@ fn parse_name<'a>(input: &'a str) -> nom::IResult<&'a str, &str> {
     )).parse(input)
 }
 
-fn parse_label<'a>(input: &'a str) -> nom::IResult<&'a str, AsmObject> {
+fn parse_label(input: &str) -> nom::IResult<&str, AsmObject> {
     // Labels must start with _a-zA-Z and can contain _a-zA-Z0-9
     let (input, (label, _colon)) = pair(
         parse_name,
         tag(":")
     ).parse(input)?;
 
-    return Ok((input, AsmObject::Label(label.to_string())));
+    Ok((input, AsmObject::Label(label.to_string())))
 }
 
-fn parse_constant<'a>(input: &'a str) -> nom::IResult<&'a str, AsmObject> {
+fn parse_constant(input: &str) -> nom::IResult<&str, AsmObject> {
     let (input, name) = parse_name(input)?;
     let (input, _) = delimited(space0, tag("="), space0).parse(input)?;
     let (input, value) = delimited(space0, alphanumeric1, parse_line_ending_comment).parse(input)?;
 
-    return Ok((input, AsmObject::Constant(name.into(), value.into())));
+    Ok((input, AsmObject::Constant(name.into(), value.into())))
 }
 
 pub fn parse_operand<'a>(input: &'a str) -> nom::IResult<&'a str, Operand> {
@@ -116,28 +116,71 @@ fn parse_instruction(input: &str) -> nom::IResult<&str, AsmObject> {
         delimited(space0,  parse_operand_list, opt(parse_line_ending_comment))
     ).parse(input)?;
 
-    return Ok((input, AsmObject::Instruction(instr.to_string(), operands)))
+    Ok((input, AsmObject::Instruction(instr.to_string(), operands)))
+}
+
+fn parse_directive(input: &str) -> nom::IResult<&str, AsmObject> {
+    // Directives are a name prefixed with '.', e.g. '.global main' or '.word 1, 2'
+    let (input, (name, operands)) = pair(
+        preceded(tag("."), parse_name),
+        delimited(space0, parse_operand_list, opt(parse_line_ending_comment))
+    ).parse(input)?;
+
+    Ok((input, AsmObject::Directive(name.to_string(), operands)))
 }
 
 pub fn parse_comment_whitespace(input: &str) -> nom::IResult<&str, &str> {
     let (input, _) = many0(alt((space1, parse_line_ending_comment))).parse(input)?;
 
-    return Ok((input, ""))
+    Ok((input, ""))
+}
+
+/// Parse a hexadecimal number written with `0x` or a decimal one
+pub fn parse_u32(text: &str) -> Option<u32> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse::<u32>().ok(),
+    }
+}
+
+/// Skip whitespace, newlines included, and `#` comments
+fn parse_blank(input: &str) -> nom::IResult<&str, ()> {
+    let (input, _) = many0(alt((
+        multispace1,
+        recognize(pair(tag("#"), not_line_ending)),
+    ))).parse(input)?;
+
+    Ok((input, ()))
+}
+
+/// Run `inner` with any blank space and comments around it skipped
+pub fn ws<'a, O, P>(inner: P) -> impl Parser<&'a str, Output = O, Error = nom::error::Error<&'a str>>
+where
+    P: Parser<&'a str, Output = O, Error = nom::error::Error<&'a str>>,
+{
+    delimited(parse_blank, inner, parse_blank)
 }
 
-pub fn parse_asm<'a>(input: &'a str) -> Result<Vec<AsmObject>, Box<dyn Error>> {
-    let (_input, objects) = many0(delimited(parse_comment_whitespace, alt((
+/// Parse a source file into objects, each with the line it starts on
+pub fn parse_asm<'a>(input: &'a str) -> Result<Vec<(u32, AsmObject)>, Box<dyn Error>> {
+    let offset = |i: &'a str| -> nom::IResult<&'a str, usize> { Ok((i, input.len() - i.len())) };
+
+    let (_input, objects) = many0(delimited(parse_comment_whitespace, pair(offset, alt((
         parse_constant,
         parse_label,
+        parse_directive,
         parse_instruction,
-    )), parse_comment_whitespace)).parse(input)
+    ))), parse_comment_whitespace)).parse(input)
         .map_err(|e| e.to_owned())?;
 
-    return Ok(objects);
+    Ok(objects.into_iter()
+        .map(|(offset, obj)| (input[..offset].matches('\n').count() as u32 + 1, obj))
+        .collect())
 }
 
+#[cfg(test)]
 mod tests {
-    use crate::parser::{parse_operand, Operand};
+    use crate::parser::{parse_operand, parse_u32, Operand};
 
     // Make sure hex numbers get parsed as operands correctly
     #[test]
@@ -151,4 +194,13 @@ mod tests {
             _ => panic!("Expected Immediate operand"),
         }
     }
+
+    #[test]
+    fn test_parse_u32() {
+        assert_eq!(parse_u32("0x10000"), Some(0x10000));
+        assert_eq!(parse_u32("4096"), Some(4096));
+        assert_eq!(parse_u32("0x"), None);
+        assert_eq!(parse_u32("4294967296"), None);
+        assert_eq!(parse_u32("ten"), None);
+    }
 }
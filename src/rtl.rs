@@ -0,0 +1,166 @@
+use crate::isa::{Field, InsnDef, Isa, Operation};
+
+fn upper(name: &str) -> String {
+    name.to_uppercase()
+}
+
+fn binary(value: u16, width: u32) -> String {
+    format!("{}'b{:0width$b}", width, value, width = width as usize)
+}
+
+/// Bits of the field as a SystemVerilog part select of `insn`
+fn select(field: &Field) -> String {
+    if field.width == 1 {
+        format!("insn[{}]", field.lsb)
+    } else {
+        format!("insn[{}:{}]", field.lsb + field.width - 1, field.lsb)
+    }
+}
+
+/// A `casez` pattern matching exactly the words `Isa::def_of` gives `def`
+/// for: the opcode and fixed fields as given, operand fields as `?` and
+/// every other bit zero
+fn pattern(isa: &Isa, def: &InsnDef) -> String {
+    let mut bits = vec!['0'; isa.word_bits as usize];
+    let mut set = |field: &Field, value: Option<u16>| {
+        for bit in 0..field.width {
+            let c = match value {
+                Some(value) => if value >> bit & 1 == 1 { '1' } else { '0' },
+                None => '?',
+            };
+            bits[(isa.word_bits - 1 - field.lsb - bit) as usize] = c;
+        }
+    };
+
+    for field in &def.fields {
+        set(field, None);
+    }
+    for (name, value) in &def.fixed {
+        set(def.field(name).unwrap(), Some(*value));
+    }
+    set(&isa.opcode, Some(def.opcode));
+
+    format!("{}'b{}", isa.word_bits, bits.into_iter().collect::<String>())
+}
+
+/// Output ports of the decoder, one per field name, as wide as the widest
+/// field of that name. Fixed fields are part of the operation instead.
+fn ports(isa: &Isa) -> Vec<(String, u32)> {
+    let mut ports = Vec::<(String, u32)>::new();
+
+    for def in &isa.instructions {
+        for field in def.fields.iter().filter(|field| !def.fixed.iter().any(|(name, _)| *name == field.name)) {
+            match ports.iter_mut().find(|(name, _)| *name == field.name) {
+                Some((_, width)) => *width = (*width).max(field.width),
+                None => ports.push((field.name.clone(), field.width)),
+            }
+        }
+    }
+
+    ports
+}
+
+/// A SystemVerilog package with the encodings of `isa` and a combinational
+/// decoder. The decoder accepts the same words as `Isa::decode`, so
+/// hardware and assembler can't disagree about what a word means.
+///
+/// `prefix` names the package `<prefix>_isa_pkg` and the module
+/// `<prefix>_decode`.
+pub fn generate(isa: &Isa, prefix: &str) -> String {
+    let word = isa.word_bits;
+    let opcode_width = isa.opcode.width;
+    let operations = isa.instructions.iter().map(|def| def.operation).fold(Vec::<Operation>::new(), |mut ops, op| {
+        if !ops.contains(&op) {
+            ops.push(op);
+        }
+        ops
+    });
+
+    let mut out = String::from("// Generated by `sasm gen-rtl` from the ISA table, do not edit\n\n");
+
+    out += &format!("package {}_isa_pkg;\n", prefix);
+    out += &format!("    localparam int WORD_BITS = {};\n", word);
+    out += &format!("    localparam int REGISTERS = {};\n\n", isa.registers);
+
+    out += &format!("    // Opcodes, in {}\n", select(&isa.opcode));
+    for op in &operations {
+        let def = isa.def_for(*op).unwrap();
+        out += &format!("    localparam logic [{}:0] OP_{} = {};\n", opcode_width - 1, upper(op.name()), binary(def.opcode, opcode_width));
+    }
+
+    let fixed = isa.instructions.iter()
+        .flat_map(|def| def.fixed.iter().map(move |(name, value)| (def, name, *value)))
+        .collect::<Vec<(&InsnDef, &String, u16)>>();
+    if !fixed.is_empty() {
+        out += "\n    // Fields whose value is part of the encoding, e.g. branch conditions\n";
+        for (def, name, value) in fixed {
+            let width = def.field(name).unwrap().width;
+            out += &format!("    localparam logic [{}:0] {}_{} = {};\n", width - 1, upper(name), upper(def.operation.name()), binary(value, width));
+        }
+    }
+
+    out += "\n    typedef enum {\n";
+    for op in &operations {
+        out += &format!("        {},\n", upper(op.name()));
+    }
+    out += "        INVALID\n    } operation_t;\nendpackage\n\n";
+
+    let ports = ports(isa);
+
+    out += &format!("module {}_decode\n    import {}_isa_pkg::*;\n(\n", prefix, prefix);
+    out += &format!("    input  logic [{}:0] insn,\n", word - 1);
+    out += "    output operation_t op,\n";
+    out += "    output logic valid";
+    for (name, width) in &ports {
+        out += &format!(",\n    output logic [{}:0] {}", width - 1, name);
+    }
+    out += "\n);\n    always_comb begin\n";
+    out += "        op = INVALID;\n        valid = 1'b0;\n";
+    for (name, _) in &ports {
+        out += &format!("        {} = '0;\n", name);
+    }
+
+    out += "\n        // In table order, the first match wins\n        casez (insn)\n";
+    for def in &isa.instructions {
+        out += &format!("            {}: begin // {}\n", pattern(isa, def), def.mnemonic);
+        out += &format!("                op = {};\n                valid = 1'b1;\n", upper(def.operation.name()));
+
+        for field in def.fields.iter().filter(|field| !def.fixed.iter().any(|(name, _)| *name == field.name)) {
+            let (_, width) = ports.iter().find(|(name, _)| *name == field.name).unwrap();
+            let bits = select(field);
+            let value = match (field.width < *width, field.signed) {
+                (false, _) => bits,
+                (true, false) => format!("{}'({})", width, bits),
+                (true, true) => format!("{}'($signed({}))", width, bits),
+            };
+
+            out += &format!("                {} = {};\n", field.name, value);
+        }
+
+        out += "            end\n";
+    }
+    out += "            default: ;\n        endcase\n    end\nendmodule\n";
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::isa::Isa;
+    use crate::rtl::generate;
+
+    #[test]
+    fn test_generate() {
+        let rtl = generate(&Isa::builtin(), "sasm");
+
+        assert!(rtl.contains("package sasm_isa_pkg;\n"));
+        assert!(rtl.contains("    localparam logic [4:0] OP_LI = 5'b10100;\n"));
+        assert!(rtl.contains("    localparam logic [1:0] COND_BLT = 2'b10;\n"));
+        assert!(rtl.contains("    output logic [10:0] addr,\n    output logic [7:0] imm\n);\n"));
+        assert!(rtl.contains("            16'b10100???????????: begin // li\n                op = LI;\n                valid = 1'b1;\n                rd = 4'(insn[10:8]);\n"));
+
+        // Reserved bits of jmp rN must be zero, like in Isa::decode
+        assert!(rtl.contains("            16'b111110000000????: begin // jmp\n                op = JMP_REG;\n"));
+        assert!(rtl.contains("            16'b1110011?????????: begin // bge\n                op = BGE;\n                valid = 1'b1;\n                off = insn[8:0];\n"));
+    }
+}
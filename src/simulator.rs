@@ -0,0 +1,535 @@
+use std::collections::VecDeque;
+use std::error::{self, Error};
+use std::sync::mpsc::Receiver;
+
+use crate::bytecode::{decode, Condition, Instruction, Opcode};
+use crate::isa::{self, Operation};
+use crate::devices::{IoEvent, MappedDevice};
+use crate::linker::Image;
+
+pub const DEFAULT_MEMORY_SIZE: u32 = 0x10000;
+
+#[derive(Debug, Clone)]
+pub struct SimError(pub String);
+
+impl std::fmt::Display for SimError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Simulation error: {}.", self.0)
+    }
+}
+
+impl error::Error for SimError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    // The PC left the program
+    EndOfProgram,
+    // A jump or taken branch to itself, the usual way to idle
+    SelfLoop,
+    StepLimit,
+}
+
+impl std::fmt::Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            StopReason::EndOfProgram => "end of program",
+            StopReason::SelfLoop => "jump to self",
+            StopReason::StepLimit => "step limit reached",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemAccess {
+    pub addr: u32,
+    pub value: u32,
+    pub write: bool,
+}
+
+/// What the last executed instruction did, for tracing
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StepInfo {
+    pub pc: u32,
+    pub insn: u16,
+    // Writes to r0 are dropped and not recorded
+    pub reg_write: Option<(u16, u32)>,
+    pub mem_access: Option<MemAccess>,
+}
+
+/// Notified after every instruction executed by `Machine::run`, see `Machine::last`
+pub trait StepObserver {
+    fn on_step(&mut self, machine: &Machine) -> Result<(), Box<dyn Error>>;
+}
+
+/// Flags are set by every ALU instruction and tested by branches
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Flags {
+    pub zero: bool,
+    pub negative: bool,
+}
+
+impl Flags {
+    fn test(&self, cond: Condition) -> bool {
+        match cond {
+            Condition::Equal => self.zero,
+            Condition::NotEqual => !self.zero,
+            Condition::LessThan => self.negative,
+            Condition::GreaterThanEqual => !self.negative,
+        }
+    }
+}
+
+/// Executes the 16-bit encodings produced by `bytecode::encode_instruction`.
+///
+/// The PC counts instructions and memory is in bytes with big-endian data
+/// words. Jump targets, branch offsets and the addresses lw and sw use are
+/// converted from what the program sees with `isa::Addressing`. r0 always
+/// reads as zero. Devices are checked before memory, so they can shadow RAM.
+pub struct Machine {
+    pub regs: [u32; 16],
+    pub pc: u32,
+    pub flags: Flags,
+    pub text: Vec<u16>,
+    pub text_base: u32,
+    pub memory: Vec<u8>,
+    pub steps: u64,
+    pub last: StepInfo,
+    pub devices: Vec<MappedDevice>,
+    // Scripted inputs, sorted by step
+    pub io_events: VecDeque<IoEvent>,
+    // Inputs typed on the terminal while running
+    pub io_input: Option<Receiver<(String, u32)>>,
+}
+
+impl Machine {
+    pub fn new(image: &Image, memory_size: u32) -> Result<Machine, SimError> {
+        let mut memory = vec![0; memory_size as usize];
+
+        let data_end = image.data_base as usize + image.data.len();
+        if data_end > memory.len() {
+            return Err(SimError(format!(".data ends at {:#x}, past the end of memory", data_end)));
+        }
+        memory[image.data_base as usize..data_end].copy_from_slice(&image.data);
+
+        Ok(Machine {
+            regs: [0; 16],
+            pc: image.text_base,
+            flags: Flags::default(),
+            text: image.text.clone(),
+            text_base: image.text_base,
+            memory,
+            steps: 0,
+            last: StepInfo::default(),
+            devices: Vec::new(),
+            io_events: VecDeque::new(),
+            io_input: None,
+        })
+    }
+
+    pub fn set_input(&mut self, name: &str, value: u32) -> Result<(), SimError> {
+        let device = self.devices.iter_mut()
+            .find(|mapped| mapped.device.name() == name)
+            .ok_or_else(|| SimError(format!("no device named '{}'", name)))?;
+
+        if !device.device.set_input(value) {
+            return Err(SimError(format!("'{}' is not an input", name)));
+        }
+
+        Ok(())
+    }
+
+    fn apply_inputs(&mut self) -> Result<(), SimError> {
+        while self.io_events.front().is_some_and(|event| event.step <= self.steps) {
+            let event = self.io_events.pop_front().unwrap();
+            self.set_input(&event.device, event.value)?;
+        }
+
+        let typed = self.io_input.as_ref()
+            .map(|rx| rx.try_iter().collect::<Vec<(String, u32)>>())
+            .unwrap_or_default();
+
+        for (device, value) in typed {
+            // A typo on the terminal shouldn't stop the program
+            if let Err(e) = self.set_input(&device, value) {
+                eprintln!("{}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn fetch(&self, addr: u32) -> Option<u16> {
+        let idx = addr.checked_sub(self.text_base)?;
+        self.text.get(idx as usize).copied()
+    }
+
+    fn set_reg(&mut self, reg: u16, value: u32) {
+        if reg != 0 {
+            self.regs[reg as usize] = value;
+            self.last.reg_write = Some((reg, value));
+        }
+    }
+
+    fn check_alignment(&self, addr: u32, size: u32) -> Result<(), SimError> {
+        if !addr.is_multiple_of(size) {
+            return Err(SimError(format!("unaligned access to {:#x} at pc {:#x}", addr, self.pc)));
+        }
+
+        Ok(())
+    }
+
+    fn check_access(&self, addr: u32, size: u32) -> Result<usize, SimError> {
+        if addr as usize + size as usize > self.memory.len() {
+            return Err(SimError(format!("access to {:#x} outside of memory at pc {:#x}", addr, self.pc)));
+        }
+
+        Ok(addr as usize)
+    }
+
+    fn load(&self, addr: u32, size: u32) -> Result<u32, SimError> {
+        self.check_alignment(addr, size)?;
+
+        let mask = u32::MAX >> (32 - 8 * size);
+        if let Some(mapped) = self.devices.iter().find(|mapped| mapped.contains(addr)) {
+            // Part of a 32-bit register, big-endian like memory
+            let offset = addr - mapped.base;
+            let shift = 8 * (4 - offset % 4 - size);
+            return Ok((mapped.device.read(offset & !3) >> shift) & mask);
+        }
+
+        let addr = self.check_access(addr, size)?;
+        let value = self.memory[addr..addr + size as usize].iter().fold(0, |value, b| value << 8 | *b as u32);
+
+        Ok(value)
+    }
+
+    fn store(&mut self, addr: u32, size: u32, value: u32) -> Result<(), SimError> {
+        self.check_alignment(addr, size)?;
+
+        let mask = u32::MAX >> (32 - 8 * size);
+        if let Some(mapped) = self.devices.iter_mut().find(|mapped| mapped.contains(addr)) {
+            // Only the addressed bytes of the register change
+            let offset = addr - mapped.base;
+            let shift = 8 * (4 - offset % 4 - size);
+            let register = mapped.device.read(offset & !3);
+            mapped.device.write(offset & !3, (register & !(mask << shift)) | ((value & mask) << shift));
+            return Ok(());
+        }
+
+        let addr = self.check_access(addr, size)?;
+        self.memory[addr..addr + size as usize].copy_from_slice(&value.to_be_bytes()[4 - size as usize..]);
+
+        Ok(())
+    }
+
+    /// The 32 bits at a byte address
+    pub fn load_word(&self, addr: u32) -> Result<u32, SimError> {
+        self.load(addr, 4)
+    }
+
+    pub fn store_word(&mut self, addr: u32, value: u32) -> Result<(), SimError> {
+        self.store(addr, 4, value)
+    }
+
+    /// The data word at a byte address, what lw reads
+    pub fn load_data(&self, addr: u32) -> Result<u32, SimError> {
+        self.load(addr, isa::current().addressing.data_word_bytes)
+    }
+
+    /// The instruction at a program address, for jumps
+    fn text_index(&self, addr: u32) -> Result<u32, SimError> {
+        isa::current().addressing.text_index(addr)
+            .ok_or_else(|| SimError(format!("jump to {:#x}, which is not the start of an instruction, at pc {:#x}", addr, self.pc)))
+    }
+
+    /// Execute one instruction, returning why the machine stopped if it did
+    pub fn step(&mut self) -> Result<Option<StopReason>, SimError> {
+        self.apply_inputs()?;
+
+        let Some(insn) = self.fetch(self.pc) else {
+            return Ok(Some(StopReason::EndOfProgram));
+        };
+
+        self.last = StepInfo { pc: self.pc, insn, ..Default::default() };
+
+        let decoded = decode(insn)
+            .map_err(|e| SimError(format!("{} at pc {:#x}", e, self.pc)))?;
+        let mut next_pc = self.pc + 1;
+
+        match decoded {
+            Instruction::Alu(opcode, rd, rt, rs) => {
+                let a = self.regs[rt as usize];
+                let b = self.regs[rs as usize];
+
+                let result = match opcode {
+                    Opcode::Add => a.wrapping_add(b),
+                    Opcode::Sub => a.wrapping_sub(b),
+                    Opcode::Or => a | b,
+                    Opcode::And => a & b,
+                    Opcode::Xor => a ^ b,
+                    Opcode::Not => !a,
+                    Opcode::Shl => a.checked_shl(b).unwrap_or(0),
+                    Opcode::Mul => a.wrapping_mul(b),
+                    Opcode::Cmp => a.wrapping_sub(b),
+                    // Shifting by 31 or more leaves only copies of the sign bit
+                    Opcode::Sar => ((a as i32) >> b.min(31)) as u32,
+                    Opcode::Rotr => a.rotate_right(b),
+                    Opcode::Rotl => a.rotate_left(b),
+                    _ => a.checked_shr(b).unwrap_or(0),
+                };
+
+                self.flags = Flags { zero: result == 0, negative: (result as i32) < 0 };
+                if opcode != Opcode::Cmp {
+                    self.set_reg(rd, result);
+                }
+            },
+            Instruction::AluImm(_, rd, imm) => {
+                // addi is the only one
+                let field = isa::current().def_for(decoded.operation()).and_then(|def| def.field("imm")).unwrap();
+                let result = self.regs[rd as usize].wrapping_add(field.value(imm) as u32);
+
+                self.flags = Flags { zero: result == 0, negative: (result as i32) < 0 };
+                self.set_reg(rd, result);
+            },
+            Instruction::Mem(opcode, rd, rt, off) => {
+                // The offset counts data words, or bytes for the byte accesses
+                let addressing = isa::current().addressing;
+                let size = match opcode {
+                    Opcode::Lw | Opcode::Sw => addressing.data_word_bytes,
+                    _ => 1,
+                };
+                let addr = addressing.data_byte(self.regs[rt as usize]).wrapping_add(off as u32 * size);
+
+                if matches!(opcode, Opcode::Lw | Opcode::Lb | Opcode::Lbu) {
+                    let value = match opcode {
+                        Opcode::Lb => self.load(addr, 1)? as u8 as i8 as u32,
+                        _ => self.load(addr, size)?,
+                    };
+                    self.set_reg(rd, value);
+                    self.last.mem_access = Some(MemAccess { addr, value, write: false });
+                } else {
+                    let value = self.regs[rd as usize] & (u32::MAX >> (32 - 8 * size));
+                    self.store(addr, size, value)?;
+                    self.last.mem_access = Some(MemAccess { addr, value, write: true });
+                }
+            },
+            Instruction::Branch(cond, off) => {
+                if self.flags.test(cond) {
+                    // Relative to the next instruction
+                    let field = isa::current().def_for(Operation::Branch(cond)).and_then(|def| def.field("off")).unwrap();
+                    let step = isa::current().addressing.text_step() as i32;
+                    if field.value(off) % step != 0 {
+                        return Err(SimError(format!("branch offset {} is not a whole instruction at pc {:#x}", field.value(off), self.pc)));
+                    }
+
+                    next_pc = next_pc.checked_add_signed(field.value(off) / step)
+                        .ok_or_else(|| SimError(format!("branch offset {} at pc {:#x} is before the start of the program", field.value(off), self.pc)))?;
+                }
+            },
+            Instruction::Jump(addr) => next_pc = self.text_index(addr as u32)?,
+            Instruction::JumpReg(rd) => next_pc = self.text_index(self.regs[rd as usize])?,
+            Instruction::JumpLink(rd, rt) => {
+                // Read before the link, rd may be rt
+                next_pc = self.text_index(self.regs[rt as usize])?;
+                self.set_reg(rd, isa::current().addressing.text_addr(self.pc + 1));
+            },
+            Instruction::Li(rd, imm) => self.set_reg(rd, imm as u32),
+        }
+
+        self.steps += 1;
+
+        if next_pc == self.pc {
+            return Ok(Some(StopReason::SelfLoop));
+        }
+
+        self.pc = next_pc;
+        Ok(None)
+    }
+
+    pub fn run(&mut self, max_steps: u64, observers: &mut [&mut dyn StepObserver]) -> Result<StopReason, Box<dyn Error>> {
+        while self.steps < max_steps {
+            let reason = self.step()?;
+
+            // Stopping at the end of the program doesn't execute anything
+            if reason != Some(StopReason::EndOfProgram) {
+                for observer in observers.iter_mut() {
+                    observer.on_step(self)?;
+                }
+            }
+
+            if let Some(reason) = reason {
+                return Ok(reason);
+            }
+        }
+
+        Ok(StopReason::StepLimit)
+    }
+
+    pub fn dump_registers(&self) -> String {
+        let mut out = format!("pc  = {:#06x}  steps = {}\n", self.pc, self.steps);
+
+        for (i, reg) in self.regs.iter().enumerate() {
+            let name = format!("r{}", i);
+            out += &format!("{:<3} = {:#010x}", name, reg);
+            out += if i % 4 == 3 { "\n" } else { "  " };
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble;
+    use crate::devices::{board_devices, parse_io_script};
+    use crate::layout::MemoryLayout;
+    use crate::linker::link;
+    use crate::simulator::{Machine, StopReason, DEFAULT_MEMORY_SIZE};
+
+    fn run(source: &str) -> Machine {
+        let image = link(&[assemble(source).unwrap()], &MemoryLayout::default()).unwrap();
+        let mut machine = Machine::new(&image, DEFAULT_MEMORY_SIZE).unwrap();
+
+        machine.run(1000, &mut []).unwrap();
+        machine
+    }
+
+    // Sum 5 + 4 + 3 + 2 + 1 with a backward branch, then store it through the stack
+    #[test]
+    fn test_run_loop() {
+        let machine = run("
+            li  r1, 5
+            li  r2, 1
+            xor r3, r0, r0
+        loop:
+            add r3, r3, r1
+            sub r1, r1, r2
+            bne loop
+            li  r4, 1
+            li  r5, 16
+            shl r15, r4, r5
+            push r3
+            pop r6
+        ");
+
+        assert_eq!(machine.regs[3], 15);
+        assert_eq!(machine.regs[6], 15);
+        assert_eq!(machine.regs[15], 0x10000);
+        assert_eq!(machine.load_word(0xfffc).unwrap(), 15);
+    }
+
+    #[test]
+    fn test_self_loop_halts() {
+        let image = link(&[assemble("li r1, 2\nend:\n    jmp end\n").unwrap()], &MemoryLayout::default()).unwrap();
+        let mut machine = Machine::new(&image, DEFAULT_MEMORY_SIZE).unwrap();
+
+        assert_eq!(machine.run(1000, &mut []).unwrap(), StopReason::SelfLoop);
+        assert_eq!(machine.pc, 1);
+    }
+
+    // Wait for btnC like test.asm does, then copy the switches to the 7-segment display
+    #[test]
+    fn test_board_io() {
+        let source = "
+            li  r1, 1
+            li  r2, 16
+            shl r14, r1, r2
+        wait:
+            lw  r1, r14, 2
+            cmp r1, r0
+            beq wait
+            lw  r1, r14, 1
+            sw  r1, r14, 0
+        end:
+            jmp end
+        ";
+        let image = link(&[assemble(source).unwrap()], &MemoryLayout::default()).unwrap();
+        let mut machine = Machine::new(&image, DEFAULT_MEMORY_SIZE).unwrap();
+        machine.devices = board_devices();
+        machine.io_events = parse_io_script("20 switches 0x42\n30 btnC 1\n").unwrap().into();
+
+        assert_eq!(machine.run(1000, &mut []).unwrap(), StopReason::SelfLoop);
+        assert_eq!(machine.load_word(0x10000).unwrap(), 0x42);
+        assert!(machine.steps > 30);
+    }
+
+    #[test]
+    fn test_branch_before_start() {
+        let image = link(&[assemble("cmp r0, r0\nbeq -20\n").unwrap()], &MemoryLayout::default()).unwrap();
+        let mut machine = Machine::new(&image, DEFAULT_MEMORY_SIZE).unwrap();
+
+        let err = machine.run(1000, &mut []).unwrap_err();
+        assert!(err.to_string().contains("before the start of the program"), "{}", err);
+        assert_eq!(machine.pc, 1);
+    }
+
+    // A call through a register and the return, then a jump table entry
+    #[test]
+    fn test_jump_and_link() {
+        let machine = run("
+            li   r1, double
+            li   r2, 5
+            jalr r6, r1
+            li   r3, table
+            add  r3, r3, r2
+            jalr r3, r3
+        double:
+            add  r2, r2, r2
+            jmp  r6
+        table:
+        ");
+
+        assert_eq!(machine.regs[2], 10);
+        assert_eq!(machine.regs[6], 3);
+        // Jumped to table + 10, linking with the address after the second jalr
+        assert_eq!((machine.pc, machine.regs[3]), (18, 6));
+    }
+
+    // Bytes of device registers are addressed like bytes of memory
+    #[test]
+    fn test_device_byte_access() {
+        let source = "
+            li  r1, 1
+            li  r2, 16
+            shl r14, r1, r2
+            li  r1, 0x12
+            sb  r1, r14, 2
+            li  r1, 0x34
+            sb  r1, r14, 3
+            lb  r3, r14, 6
+            lbu r4, r14, 6
+            lbu r5, r14, 7
+        ";
+        let image = link(&[assemble(source).unwrap()], &MemoryLayout::default()).unwrap();
+        let mut machine = Machine::new(&image, DEFAULT_MEMORY_SIZE).unwrap();
+        machine.devices = board_devices();
+        machine.set_input("switches", 0x80ff).unwrap();
+
+        machine.run(1000, &mut []).unwrap();
+        assert_eq!(machine.load_word(0x10000).unwrap(), 0x1234);
+        assert_eq!((machine.regs[3], machine.regs[4], machine.regs[5]), (0xffffff80, 0x80, 0xff));
+    }
+
+    // Big-endian, so byte 3 of a word is its low byte
+    #[test]
+    fn test_byte_access() {
+        let machine = run("
+            li  r1, 0x80
+            li  r2, 0x7f
+            li  r3, 0x20
+            sb  r1, r3, 3
+            sb  r2, r3, 4
+            lb  r4, r3, 3
+            lbu r5, r3, 3
+            lb  r6, r3, 4
+            lw  r7, r3, 0
+        ");
+
+        assert_eq!(machine.regs[4], 0xffffff80);
+        assert_eq!(machine.regs[5], 0x80);
+        assert_eq!(machine.regs[6], 0x7f);
+        assert_eq!(machine.regs[7], 0x80);
+        assert_eq!(machine.load_word(0x24).unwrap(), 0x7f000000);
+    }
+}
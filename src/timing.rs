@@ -0,0 +1,215 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use crate::bytecode::{decode, Instruction, Opcode};
+use crate::linker::Image;
+use crate::simulator::{Machine, StepObserver};
+
+#[derive(Debug, Clone, Copy)]
+pub struct TimingConfig {
+    pub stages: u32,
+    // Cycles lost when a branch is taken or a jump redirects fetch
+    pub branch_penalty: u32,
+    // Cycles an instruction waits for a register loaded by the one before it
+    pub load_use_stall: u32,
+    // Extra cycles for every lw/sw
+    pub mem_latency: u32,
+}
+
+impl Default for TimingConfig {
+    fn default() -> Self {
+        TimingConfig { stages: 5, branch_penalty: 2, load_use_stall: 1, mem_latency: 0 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stalls {
+    pub branch: u64,
+    pub load_use: u64,
+    pub memory: u64,
+}
+
+impl Stalls {
+    fn total(&self) -> u64 {
+        self.branch + self.load_use + self.memory
+    }
+
+    fn add(&mut self, other: &Stalls) {
+        self.branch += other.branch;
+        self.load_use += other.load_use;
+        self.memory += other.memory;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct PcStats {
+    count: u64,
+    stalls: Stalls,
+}
+
+/// Registers an instruction word reads
+fn source_regs(insn: u16) -> Vec<u16> {
+    match decode(insn) {
+        Ok(Instruction::Alu(Opcode::Not, _, rt, _)) => vec![rt],
+        Ok(Instruction::Alu(_, _, rt, rs)) => vec![rt, rs],
+        Ok(Instruction::Mem(Opcode::Sw | Opcode::Sb, rd, rt, _)) => vec![rt, rd],
+        Ok(Instruction::Mem(_, _, rt, _)) => vec![rt],
+        Ok(Instruction::AluImm(_, rd, _) | Instruction::JumpReg(rd)) => vec![rd],
+        Ok(Instruction::JumpLink(_, rt)) => vec![rt],
+        Ok(Instruction::Branch(..) | Instruction::Jump(_) | Instruction::Li(..)) | Err(_) => vec![],
+    }
+}
+
+/// Estimates cycles on an in-order pipeline from the instructions the
+/// simulator retires. Stalls are charged to the instruction that waits.
+pub struct TimingModel {
+    config: TimingConfig,
+    instructions: u64,
+    // Destination of the previous instruction if it was a load
+    pending_load: Option<u16>,
+    per_pc: BTreeMap<u32, PcStats>,
+}
+
+impl TimingModel {
+    pub fn new(config: TimingConfig) -> TimingModel {
+        TimingModel { config, instructions: 0, pending_load: None, per_pc: BTreeMap::new() }
+    }
+
+    pub fn stalls(&self) -> Stalls {
+        let mut total = Stalls::default();
+        for stats in self.per_pc.values() {
+            total.add(&stats.stalls);
+        }
+
+        total
+    }
+
+    pub fn cycles(&self) -> u64 {
+        if self.instructions == 0 {
+            return 0;
+        }
+
+        // Filling the pipeline, then one instruction a cycle plus stalls
+        (self.config.stages as u64 - 1) + self.instructions + self.stalls().total()
+    }
+
+    pub fn cpi(&self) -> f64 {
+        if self.instructions == 0 {
+            return 0.0;
+        }
+
+        self.cycles() as f64 / self.instructions as f64
+    }
+
+    /// Summary followed by stalls per source line, or per address if the
+    /// program has no line information
+    pub fn report(&self, image: &Image, source: &str) -> String {
+        let stalls = self.stalls();
+        let mut out = format!(
+            "cycles: {}  instructions: {}  CPI: {:.2}\nstalls: branch {}, load-use {}, memory {}\n",
+            self.cycles(), self.instructions, self.cpi(), stalls.branch, stalls.load_use, stalls.memory
+        );
+
+        let mut per_line = BTreeMap::<Option<u32>, (u32, PcStats)>::new();
+        for (pc, stats) in &self.per_pc {
+            let line = image.line_at(*pc);
+            let entry = per_line.entry(line).or_insert((*pc, PcStats::default()));
+
+            entry.1.count = entry.1.count.max(stats.count);
+            entry.1.stalls.add(&stats.stalls);
+        }
+
+        let source = source.lines().collect::<Vec<&str>>();
+
+        out += &format!("{:>6} {:>8} {:>8} {:>8} {:>8}  source\n", "line", "count", "branch", "load-use", "memory");
+        for (line, (pc, stats)) in per_line {
+            if stats.stalls.total() == 0 {
+                continue;
+            }
+
+            let (line, text) = match line {
+                Some(line) => (line.to_string(), source.get(line as usize - 1).map_or("", |text| text.trim())),
+                None => (format!("{:#06x}", pc), ""),
+            };
+
+            out += &format!(
+                "{:>6} {:>8} {:>8} {:>8} {:>8}  {}\n",
+                line, stats.count, stats.stalls.branch, stats.stalls.load_use, stats.stalls.memory, text
+            );
+        }
+
+        out
+    }
+}
+
+impl StepObserver for TimingModel {
+    fn on_step(&mut self, machine: &Machine) -> Result<(), Box<dyn Error>> {
+        let last = &machine.last;
+        let mut stalls = Stalls::default();
+
+        if let Some(reg) = self.pending_load {
+            if source_regs(last.insn).contains(&reg) {
+                stalls.load_use = self.config.load_use_stall as u64;
+            }
+        }
+
+        if last.mem_access.is_some() {
+            stalls.memory = self.config.mem_latency as u64;
+        }
+
+        // Anything that didn't fall through to the next word redirected fetch
+        if machine.pc != last.pc + 1 {
+            stalls.branch = self.config.branch_penalty as u64;
+        }
+
+        self.pending_load = match decode(last.insn) {
+            Ok(Instruction::Mem(Opcode::Lw | Opcode::Lb | Opcode::Lbu, rd, ..)) => Some(rd).filter(|reg| *reg != 0),
+            _ => None,
+        };
+
+        self.instructions += 1;
+
+        let stats = self.per_pc.entry(last.pc).or_default();
+        stats.count += 1;
+        stats.stalls.add(&stalls);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble;
+    use crate::layout::MemoryLayout;
+    use crate::linker::link;
+    use crate::simulator::{Machine, DEFAULT_MEMORY_SIZE};
+    use crate::timing::{TimingConfig, TimingModel};
+
+    #[test]
+    fn test_timing_stalls() {
+        let source = "
+            li  r1, 2
+            li  r2, 1
+        loop:
+            lw  r3, r0, 0
+            add r4, r3, r3
+            sub r1, r1, r2
+            bne loop
+        ";
+        let image = link(&[assemble(source).unwrap()], &MemoryLayout::default()).unwrap();
+        let mut machine = Machine::new(&image, DEFAULT_MEMORY_SIZE).unwrap();
+
+        let config = TimingConfig { stages: 5, branch_penalty: 2, load_use_stall: 1, mem_latency: 3 };
+        let mut timing = TimingModel::new(config);
+        machine.run(100, &mut [&mut timing]).unwrap();
+
+        // 10 instructions, one taken branch, two load-use stalls, two loads
+        let stalls = timing.stalls();
+        assert_eq!((stalls.branch, stalls.load_use, stalls.memory), (2, 2, 6));
+        assert_eq!(timing.cycles(), 4 + 10 + 10);
+
+        let report = timing.report(&image, source);
+        assert!(report.contains("add r4, r3, r3"));
+        assert!(!report.contains("sub r1, r1, r2"));
+    }
+}
@@ -0,0 +1,171 @@
+use std::error::Error;
+use std::io::Write;
+
+use crate::disasm::format_word;
+use crate::simulator::{Machine, StepObserver};
+
+/// Writes one line per executed instruction: step, pc, encoded word,
+/// disassembly, then any register write and memory access.
+pub struct TraceLog<W: Write> {
+    out: W,
+}
+
+impl<W: Write> TraceLog<W> {
+    pub fn new(out: W) -> TraceLog<W> {
+        TraceLog { out }
+    }
+}
+
+impl<W: Write> StepObserver for TraceLog<W> {
+    fn on_step(&mut self, machine: &Machine) -> Result<(), Box<dyn Error>> {
+        let last = &machine.last;
+        let text = format_word(last.insn);
+
+        write!(self.out, "{:>8} {:#06x}  {:04x}  {:<20}", machine.steps, last.pc, last.insn, text)?;
+
+        if let Some((reg, value)) = last.reg_write {
+            write!(self.out, "  r{} <- {:#010x}", reg, value)?;
+        }
+
+        if let Some(access) = last.mem_access {
+            let kind = if access.write { "store" } else { "load" };
+            write!(self.out, "  {} [{:#010x}] = {:#010x}", kind, access.addr, access.value)?;
+        }
+
+        writeln!(self.out)?;
+        Ok(())
+    }
+}
+
+struct Signal {
+    id: String,
+    width: u32,
+    value: Option<u64>,
+}
+
+/// Dumps the retired pc and instruction, the registers and the data bus as a
+/// VCD waveform. Each instruction takes one `period`, starting from the
+/// initial state at time 0.
+pub struct VcdWriter<W: Write> {
+    out: W,
+    period: u64,
+    signals: Vec<Signal>,
+}
+
+// Signal order in VcdWriter::signals
+const PC: usize = 0;
+const INSN: usize = 1;
+const REGS: usize = 2;
+const BUS_ADDR: usize = REGS + 16;
+const BUS_DATA: usize = BUS_ADDR + 1;
+const BUS_WE: usize = BUS_ADDR + 2;
+const BUS_RE: usize = BUS_ADDR + 3;
+
+impl<W: Write> VcdWriter<W> {
+    pub fn new(mut out: W, machine: &Machine, period: u64) -> Result<VcdWriter<W>, Box<dyn Error>> {
+        let mut names = vec![("pc".to_string(), 32), ("insn".to_string(), 16)];
+        names.extend((0..16).map(|i| (format!("r{}", i), 32)));
+        names.extend([("bus_addr".to_string(), 32), ("bus_data".to_string(), 32), ("bus_we".to_string(), 1), ("bus_re".to_string(), 1)]);
+
+        writeln!(out, "$timescale 1ns $end")?;
+        writeln!(out, "$scope module sasm $end")?;
+
+        let mut signals = Vec::<Signal>::new();
+        for (i, (name, width)) in names.iter().enumerate() {
+            // Identifiers are short strings of printable characters
+            let id = ((b'!' + i as u8) as char).to_string();
+            writeln!(out, "$var wire {} {} {} $end", width, id, name)?;
+            signals.push(Signal { id, width: *width, value: None });
+        }
+
+        writeln!(out, "$upscope $end")?;
+        writeln!(out, "$enddefinitions $end")?;
+        writeln!(out, "#0")?;
+        writeln!(out, "$dumpvars")?;
+
+        let mut vcd = VcdWriter { out, period, signals };
+
+        let mut initial = vec![0u64; vcd.signals.len()];
+        initial[PC] = machine.pc as u64;
+        for (i, reg) in machine.regs.iter().enumerate() {
+            initial[REGS + i] = *reg as u64;
+        }
+
+        vcd.write_changes(&initial)?;
+        writeln!(vcd.out, "$end")?;
+
+        Ok(vcd)
+    }
+
+    fn write_changes(&mut self, values: &[u64]) -> Result<(), Box<dyn Error>> {
+        for (signal, value) in self.signals.iter_mut().zip(values) {
+            if signal.value == Some(*value) {
+                continue;
+            }
+
+            if signal.width == 1 {
+                writeln!(self.out, "{}{}", value, signal.id)?;
+            } else {
+                writeln!(self.out, "b{:b} {}", value, signal.id)?;
+            }
+
+            signal.value = Some(*value);
+        }
+
+        Ok(())
+    }
+}
+
+impl<W: Write> StepObserver for VcdWriter<W> {
+    fn on_step(&mut self, machine: &Machine) -> Result<(), Box<dyn Error>> {
+        let last = &machine.last;
+
+        let mut values = self.signals.iter().map(|signal| signal.value.unwrap_or(0)).collect::<Vec<u64>>();
+        values[PC] = last.pc as u64;
+        values[INSN] = last.insn as u64;
+        for (i, reg) in machine.regs.iter().enumerate() {
+            values[REGS + i] = *reg as u64;
+        }
+
+        // The bus holds its last address and data between accesses
+        values[BUS_WE] = 0;
+        values[BUS_RE] = 0;
+        if let Some(access) = last.mem_access {
+            values[BUS_ADDR] = access.addr as u64;
+            values[BUS_DATA] = access.value as u64;
+            values[if access.write { BUS_WE } else { BUS_RE }] = 1;
+        }
+
+        writeln!(self.out, "#{}", machine.steps * self.period)?;
+        self.write_changes(&values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble;
+    use crate::layout::MemoryLayout;
+    use crate::linker::link;
+    use crate::simulator::{Machine, DEFAULT_MEMORY_SIZE};
+    use crate::trace::{TraceLog, VcdWriter};
+
+    #[test]
+    fn test_trace_and_vcd() {
+        let image = link(&[assemble("li r1, 9\nsw r1, r0, 1\n").unwrap()], &MemoryLayout::default()).unwrap();
+        let mut machine = Machine::new(&image, DEFAULT_MEMORY_SIZE).unwrap();
+
+        let mut log = TraceLog::new(Vec::<u8>::new());
+        let mut vcd = VcdWriter::new(Vec::<u8>::new(), &machine, 10).unwrap();
+        machine.run(100, &mut [&mut log, &mut vcd]).unwrap();
+
+        let log = String::from_utf8(log.out).unwrap();
+        let lines = log.lines().collect::<Vec<&str>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("li r1, 9") && lines[0].contains("r1 <- 0x00000009"));
+        assert!(lines[1].contains("store [0x00000004] = 0x00000009"));
+
+        let vcd = String::from_utf8(vcd.out).unwrap();
+        assert!(vcd.contains("$var wire 32 $ r1 $end"));
+        assert!(vcd.ends_with("#20\nb1 !\nb1100000100000001 \"\nb100 3\nb1001 4\n15\n"));
+    }
+}
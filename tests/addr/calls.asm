@@ -0,0 +1,29 @@
+# Calls with jalr and a jump table of code addresses in .data, which hold
+# whatever the addressing model makes them
+# init r2 = 2
+# expect r3 = 20
+# expect r4 = 6
+# expect r5 = 1
+    li   r1, table
+    lw   r1, r1, 2
+    jalr r7, r1
+    li   r1, triple
+    jalr r6, r1
+    li   r5, 1
+done:
+    jmp  done
+
+double:
+    add  r3, r2, r2
+    jmp  r7
+triple:
+    add  r4, r2, r2
+    add  r4, r4, r2
+    jmp  r6
+ten:
+    li   r3, 20
+    jmp  r7
+
+.data
+table:
+    .word double, triple, ten
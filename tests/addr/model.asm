@@ -0,0 +1,43 @@
+# Only uses labels as addresses, so it runs the same whatever the
+# addressing model. .org counts in the model's units.
+# expect r2 = 12
+# expect r4 = 12
+# expect r5 = 3
+# expect r15 = 0x40
+# expect mem[sum] = 12
+    li   r1, top
+    add  r15, r1, r0
+    li   r1, values
+    lw   r2, r1, 0
+    lw   r3, r1, 1
+    add  r2, r2, r3
+    li   r5, sum
+    sw   r2, r5, 0
+    push r2
+    pop  r4
+
+    # Through a register, past the padding
+    li   r6, far
+    jmp  r6
+    li   r4, 0xff
+
+    .org 0x80
+far:
+    li   r3, 3
+    li   r6, 1
+    xor  r5, r5, r5
+loop:
+    add  r5, r5, r6
+    sub  r3, r3, r6
+    bne  loop
+    jmp  done
+done:
+    jmp  done
+
+.data
+values:
+    .word 4, 8
+sum:
+    .word 0
+    .org 0x40
+top:
@@ -0,0 +1,29 @@
+# Copy a string a byte at a time, with lb sign extending and lbu not
+# expect r4 = 4
+# expect r5 = -1
+# expect r6 = 0xff
+# expect mem[copy] = 0x73617300
+    li  r1, text
+    li  r2, copy
+    li  r3, 1
+    xor r4, r4, r4
+next:
+    lbu r5, r1, 0
+    sb  r5, r2, 0
+    add r1, r1, r3
+    add r2, r2, r3
+    add r4, r4, r3
+    cmp r5, r0
+    bne next
+
+    li  r1, minus
+    lb  r5, r1, 1
+    lbu r6, r1, 1
+
+.data
+text:
+    .word 0x73617300
+copy:
+    .word 0
+minus:
+    .word 0x00ff0000
@@ -0,0 +1,29 @@
+# The ISA extension, these only assemble with --ext
+# init r1 = 6
+# init r2 = 7
+# init r3 = -8
+# init r4 = 0x80000001
+# expect r8 = 42
+# expect r9 = -2
+# expect r10 = 0xc0000000
+# expect r11 = 3
+# expect r12 = 5
+# expect r13 = 1
+    mul  r8, r1, r2
+    li   r5, 2
+    sar  r9, r3, r5
+    li   r5, 1
+    rotr r10, r4, r5
+    rotl r11, r4, r5
+    addi r12, 9
+    addi r12, -4
+
+    # 6 - 7 is negative, and cmp leaves r0 alone
+    cmp  r1, r2
+    blt  less
+    addi r13, 2
+    jmp  end
+less:
+    addi r13, 1
+end:
+    jmp  end
@@ -0,0 +1,199 @@
+//! Assembles every `.asm` file under `tests/` and compares the binary and
+//! the listing with the files in `tests/golden/`. The binary is also
+//! disassembled and reassembled, with and without idioms, which must
+//! reproduce it exactly.
+//!
+//! Run with `UPDATE_GOLDEN=1` to regenerate the golden files after an
+//! intended change to the output.
+//!
+//! Programs in `tests/ext/` use the ISA extension, so they're run and
+//! round-tripped with `--ext` instead. Those in `tests/addr/` are run and
+//! round-tripped under several addressing models.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+fn run_sasm(args: &[&str], input: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_sasm"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn sasm(args: &[&str], input: &[u8]) -> Vec<u8> {
+    let output = run_sasm(args, input);
+
+    assert!(output.status.success(), "sasm {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+    output.stdout
+}
+
+fn asm_files(dir: &Path) -> Vec<PathBuf> {
+    let mut sources = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "asm"))
+        .collect::<Vec<PathBuf>>();
+    sources.sort();
+
+    sources
+}
+
+/// Compare against the golden file, or write it when updating.
+/// Returns a description of the mismatch, if any.
+fn check(golden: &Path, actual: &[u8]) -> Option<String> {
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::create_dir_all(golden.parent().unwrap()).unwrap();
+        fs::write(golden, actual).unwrap();
+        return None;
+    }
+
+    let Ok(expected) = fs::read(golden) else {
+        return Some(format!("{} is missing, run with UPDATE_GOLDEN=1", golden.display()));
+    };
+
+    if expected == actual {
+        return None;
+    }
+
+    let expected = String::from_utf8_lossy(&expected);
+    let actual = String::from_utf8_lossy(actual);
+    let first = expected.lines().zip(actual.lines())
+        .position(|(e, a)| e != a)
+        .unwrap_or(expected.lines().count().min(actual.lines().count()));
+
+    Some(format!(
+        "{} differs at line {}:\n  expected: {}\n  actual:   {}",
+        golden.display(),
+        first + 1,
+        expected.lines().nth(first).unwrap_or("<end of file>"),
+        actual.lines().nth(first).unwrap_or("<end of file>"),
+    ))
+}
+
+#[test]
+fn golden_outputs() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let golden = root.join("tests/golden");
+    let mut failures = Vec::<String>::new();
+
+    for source in asm_files(&root.join("tests")) {
+        let input = fs::read(&source).unwrap();
+        let stem = source.file_stem().unwrap().to_string_lossy().into_owned();
+
+        let binary = sasm(&[], &input);
+        failures.extend(check(&golden.join(format!("{}.bin", stem)), &binary));
+
+        let listing = sasm(&["--listing"], &input);
+        failures.extend(check(&golden.join(format!("{}.lst", stem)), &listing));
+
+        // The disassembly must assemble back to the same binary, also with
+        // push and pop shown as one line
+        for args in [&["--disasm"][..], &["--disasm", "--idioms"]] {
+            let disassembly = sasm(args, &binary);
+            if sasm(&[], &disassembly) != binary {
+                failures.push(format!("{} does not reassemble to the same binary with {:?}", source.display(), args));
+            }
+        }
+    }
+
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+// test.asm gives add an immediate where it takes a register
+#[test]
+fn test_asm_operand_error() {
+    let input = fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("test.asm")).unwrap();
+    let output = run_sasm(&[], &input);
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Error: InvalidOperands"));
+}
+
+#[test]
+fn extension_programs() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/ext");
+
+    // Fails if any of their expectations do
+    sasm(&["--ext", "test", dir.to_str().unwrap()], b"");
+
+    for source in asm_files(&dir) {
+        let binary = sasm(&["--ext"], &fs::read(&source).unwrap());
+        let disassembly = sasm(&["--ext", "--disasm"], &binary);
+        assert_eq!(sasm(&["--ext"], &disassembly), binary, "{} does not reassemble to the same binary", source.display());
+    }
+}
+
+#[test]
+fn addressing_models() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/addr");
+
+    let models: [&[&str]; 3] = [
+        &["--addr-unit", "byte"],
+        &["--addr-unit", "word"],
+        &["--addr-unit", "byte", "--insn-size", "4", "--data-word-size", "2"],
+    ];
+
+    for model in models {
+        sasm(&[model, &["test", dir.to_str().unwrap()]].concat(), b"");
+
+        for source in asm_files(&dir) {
+            let binary = sasm(model, &fs::read(&source).unwrap());
+            for args in [&["--disasm"][..], &["--disasm", "--idioms"]] {
+                let disassembly = sasm(&[model, args].concat(), &binary);
+                assert_eq!(sasm(model, &disassembly), binary, "{} does not reassemble with {:?} {:?}", source.display(), model, args);
+            }
+        }
+    }
+}
+
+#[test]
+fn unaligned_targets() {
+    // Instructions are two bytes, so 3 is in the middle of one
+    for source in ["jmp 3\n", "beq 3\n"] {
+        let output = run_sasm(&["--addr-unit", "byte"], source.as_bytes());
+        assert!(!output.status.success(), "{:?} assembled", source);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("does not go to the start of an instruction"));
+    }
+
+    // A binary from elsewhere can still have them, so they show as numbers
+    let binary = sasm(&[], b"jmp 3\nbeq 3\n");
+    let disassembly = sasm(&["--addr-unit", "byte", "--disasm"], &binary);
+    assert_eq!(String::from_utf8_lossy(&disassembly), "    jmp 3\n    beq 3\n");
+
+    let path = std::env::temp_dir().join(format!("sasm-unaligned-{}.bin", std::process::id()));
+    fs::write(&path, &binary).unwrap();
+    let cfg = sasm(&["--addr-unit", "byte", "cfg", path.to_str().unwrap()], b"");
+    fs::remove_file(&path).unwrap();
+
+    assert!(!String::from_utf8_lossy(&cfg).contains("->"));
+}
+
+#[test]
+fn gdb_port_out_of_range() {
+    let program = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/labels.asm");
+    let output = run_sasm(&["gdb", program.to_str().unwrap(), "--port", "70000"], b"");
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--port must be at most 65535"));
+}
+
+#[test]
+fn ext_with_isa_from_environment() {
+    let output = Command::new(env!("CARGO_BIN_EXE_sasm"))
+        .arg("--ext")
+        .env("SASM_ISA", "isa.toml")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--ext can't be used with SASM_ISA"));
+}
@@ -0,0 +1,24 @@
+# Label addresses after push/pop expansion, and branches both ways
+# init r15 = 0x100
+# expect r1 = 0
+# expect r4 = 3
+# expect r5 = 14
+# expect r6 = 15
+# expect mem[0xfc] = 3
+    li  r1, 3
+    li  r2, 1
+    xor r4, r0, r0
+loop:
+    add r4, r4, r2
+    sub r1, r1, r2
+    bne loop
+    beq done
+    li  r4, 0xff
+done:
+    push r4
+    li  r5, after
+    jmp after
+    li  r4, 0xff
+after:
+    li  r6, end
+end:
@@ -1,3 +1,11 @@
+# init r1 = 7
+# init r15 = 0x100
+# expect r1 = 7
+# expect r2 = 3
+# expect r3 = 8
+# expect r15 = 0x100
+# expect mem[0xfc] = 7
+# expect cycles <= 16
 push r1
 test:
 li  r2, test
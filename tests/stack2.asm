@@ -0,0 +1,28 @@
+# Branches over and between push/pop expansions, each three words
+# init r15 = 0x100
+# expect r1 = 0
+# expect r2 = 6
+# expect r3 = 6
+# expect r15 = 0x100
+# expect mem[0xfc] = 6
+    li  r1, 3
+    li  r4, 1
+    xor r2, r0, r0
+sum:
+    push r1
+    pop r3
+    add r2, r2, r3
+    sub r1, r1, r4
+    bne sum
+    push r2
+    # push changes the flags
+    cmp r1, r0
+    beq skip
+    push r0
+skip:
+    pop r3
+    li  r5, 0
+    add r5, r5, r5
+    beq end
+    li  r3, 0xff
+end: